@@ -0,0 +1,402 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use std::collections::VecDeque;
+
+use bytes::BytesMut;
+use futures::{future, Future, Stream, Sink, Poll, Async, StartSend, AsyncSink};
+use futures::future::{Either, Loop};
+use tokio_codec::{Decoder, Encoder, Framed};
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+use slog::Logger;
+
+use error::{Error, ErrorKind};
+use smartfox::{SmartFoxPacket, Handshake, HandshakeStep, decode_frame};
+use packet::{ServerPacket, ClientPacket, LoginStatus};
+use connection::ConnectionConfig;
+use dead_letter::DeadLetters;
+
+/// Seconds to wait for every answer during the login, like the blocking connection
+const LOGIN_TIMEOUT: u64 = 2;
+
+/// Codec for zero terminated SmartFoxServer packets
+///
+/// Frames that aren't utf8 are quarantined to the dead letters and skipped, like
+/// `SmartFoxClient::recv_packet` does.
+pub struct SmartFoxClientCodec {
+    dead_letters: DeadLetters,
+    logger: Logger,
+}
+
+impl SmartFoxClientCodec {
+    /// Create a codec which quarantines to `dead_letters`
+    pub fn new(dead_letters: DeadLetters, logger: Logger) -> Self {
+        SmartFoxClientCodec {
+            dead_letters: dead_letters,
+            logger: logger,
+        }
+    }
+}
+
+impl Decoder for SmartFoxClientCodec {
+    type Item = SmartFoxPacket;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<SmartFoxPacket>> {
+        loop {
+            let end = match buf.iter().position(|&byte| byte == 0) {
+                Some(end) => end,
+                None => return Ok(None),
+            };
+            let mut data = buf.split_to(end + 1).to_vec();
+            data.pop(); // zero terminator
+            if let Ok(data) = decode_frame(data, &self.dead_letters, &self.logger) {
+                return Ok(Some(SmartFoxPacket(data)));
+            }
+        }
+    }
+}
+
+impl Encoder for SmartFoxClientCodec {
+    type Item = SmartFoxPacket;
+    type Error = io::Error;
+
+    fn encode(&mut self, packet: SmartFoxPacket, buf: &mut BytesMut) -> io::Result<()> {
+        buf.reserve(packet.data.len() + 1);
+        buf.extend_from_slice(packet.data.as_bytes());
+        buf.extend_from_slice(&[0]);
+        Ok(())
+    }
+}
+
+type Transport = Framed<TcpStream, SmartFoxClientCodec>;
+
+type HandshakeLoop = Loop<(Transport, Option<SmartFoxPacket>), (Transport, Handshake)>;
+
+/// Asynchronous goodgame empire connection
+///
+/// It is a `Stream` of received packets and a `Sink` for packets to send. Use `Stream::split`
+/// to use them independently. Like `Connection::read_packets` kpi and irc packets are ignored.
+/// Malformed packets are quarantined to the dead letters and skipped.
+pub struct AsyncConnection {
+    transport: Transport,
+    config: ConnectionConfig,
+    /// Packets received during the login
+    pending: VecDeque<SmartFoxPacket>,
+    dead_letters: DeadLetters,
+    logger: Logger,
+}
+
+impl AsyncConnection {
+    /// Connect and login
+    ///
+    /// Does the same handshake as `Connection::new`, with the same timeouts: the future fails with
+    /// `ErrorKind::Timeout` when the version check or the lli isn't answered within two seconds,
+    /// a SmartFoxServer login that isn't answered is assumed to be accepted.
+    pub fn connect(
+        server: &SocketAddr,
+        config: ConnectionConfig,
+        un: &str,
+        pw: &str,
+        handle: &Handle,
+        logger: Logger,
+    ) -> Box<Future<Item = Self, Error = Error>> {
+        let handshake = Handshake::new(
+            &config.zone,
+            "",
+            &config.smartfox_password(),
+            logger.clone(),
+        );
+        let ver_chk = handshake.start();
+        let lli = SmartFoxPacket(
+            ClientPacket::Lli {
+                username: un.to_string(),
+                password: pw.to_string(),
+            }.to_raw_data(&config),
+        );
+
+        let dead_letters = DeadLetters::new();
+        let codec = SmartFoxClientCodec::new(dead_letters.clone(), logger.clone());
+        let handshake_handle = handle.clone();
+        let login_handle = handle.clone();
+
+        let con = TcpStream::connect(server, handle)
+            .and_then(move |stream| codec.framed(stream).send(ver_chk))
+            .map_err(Error::from)
+            .and_then(move |transport| {
+                future::loop_fn((transport, handshake), move |(transport, handshake)| {
+                    next_packet(transport, &handshake_handle).and_then(
+                        move |(packet, transport)| handshake_step(transport, handshake, packet),
+                    )
+                })
+            })
+            .and_then(move |(transport, first)| {
+                transport.send(lli).map_err(Error::from).map(move |transport| {
+                    (transport, first.into_iter().collect::<VecDeque<_>>())
+                })
+            })
+            .and_then(move |(transport, pending)| {
+                wait_for_login(transport, pending, login_handle)
+            })
+            .map(move |(transport, pending)| {
+                debug!(logger, "logged in");
+                AsyncConnection {
+                    transport: transport,
+                    config: config,
+                    pending: pending,
+                    dead_letters: dead_letters,
+                    logger: logger,
+                }
+            });
+        Box::new(con)
+    }
+
+    /// The settings of the server this connection belongs to
+    pub fn config(&self) -> &ConnectionConfig {
+        &self.config
+    }
+
+    /// Where malformed frames and packets are quarantined
+    pub fn dead_letters(&self) -> &DeadLetters {
+        &self.dead_letters
+    }
+
+    /// Quarantine a packet that couldn't be parsed
+    fn quarantine(&self, err: &Error, data: &str) {
+        warn!(self.logger, "quarantined packet"; "error" => err.to_string());
+        if let Err(err) = self.dead_letters.push(&err.to_string(), data.as_bytes()) {
+            error!(self.logger, "Couldnt quarantine packet"; "error" => err.to_string());
+        }
+    }
+}
+
+/// Receive the next packet, None when nothing arrived within `LOGIN_TIMEOUT`
+fn next_packet(
+    transport: Transport,
+    handle: &Handle,
+) -> Box<Future<Item = (Option<SmartFoxPacket>, Transport), Error = Error>> {
+    let timeout = match Timeout::new(Duration::new(LOGIN_TIMEOUT, 0), handle) {
+        Ok(timeout) => timeout,
+        Err(err) => return Box::new(future::err(err.into())),
+    };
+    Box::new(transport.into_future().select2(timeout).then(
+        |result| match result {
+            Ok(Either::A(((Some(packet), transport), _timeout))) => Ok((Some(packet), transport)),
+            Ok(Either::A(((None, _transport), _timeout))) => Err(ErrorKind::Disconnected.into()),
+            Ok(Either::B(((), next))) => {
+                let transport = next.into_inner().expect("Transport taken before the timeout");
+                Ok((None, transport))
+            }
+            Err(Either::A(((err, _), _))) |
+            Err(Either::B((err, _))) => Err(err.into()),
+        },
+    ))
+}
+
+fn handshake_step(
+    transport: Transport,
+    mut handshake: Handshake,
+    packet: Option<SmartFoxPacket>,
+) -> Box<Future<Item = HandshakeLoop, Error = Error>> {
+    let packet = match packet {
+        Some(packet) => packet,
+        None => {
+            return match handshake.timed_out() {
+                Ok(()) => Box::new(future::ok(Loop::Break((transport, None)))),
+                Err(err) => Box::new(future::err(err)),
+            }
+        }
+    };
+    match handshake.receive(packet) {
+        Ok(HandshakeStep::Send(packet)) => {
+            Box::new(transport.send(packet).map_err(Error::from).map(
+                move |transport| Loop::Continue((transport, handshake)),
+            ))
+        }
+        Ok(HandshakeStep::Wait) => Box::new(future::ok(Loop::Continue((transport, handshake)))),
+        Ok(HandshakeStep::Done(packet)) => Box::new(future::ok(Loop::Break((transport, packet)))),
+        Err(err) => Box::new(future::err(err)),
+    }
+}
+
+/// Wait for the lli answer, other packets are kept
+fn wait_for_login(
+    transport: Transport,
+    pending: VecDeque<SmartFoxPacket>,
+    handle: Handle,
+) -> Box<Future<Item = (Transport, VecDeque<SmartFoxPacket>), Error = Error>> {
+    Box::new(future::loop_fn(
+        (transport, pending),
+        move |(transport, mut pending)| {
+            next_packet(transport, &handle).and_then(move |(packet, transport)| {
+                let packet = match packet {
+                    Some(packet) => packet,
+                    None => return Err(ErrorKind::Timeout.into()),
+                };
+                match ServerPacket::new(packet.data.clone()) {
+                    Ok(ServerPacket::Lli(LoginStatus::Ok)) => Ok(Loop::Break((transport, pending))),
+                    Ok(ServerPacket::Lli(status)) => {
+                        Err(ErrorKind::LoginFailed(status.code()).into())
+                    }
+                    _ => {
+                        pending.push_back(packet);
+                        Ok(Loop::Continue((transport, pending)))
+                    }
+                }
+            })
+        },
+    ))
+}
+
+impl Stream for AsyncConnection {
+    type Item = ServerPacket;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<ServerPacket>, Error> {
+        loop {
+            let packet = match self.pending.pop_front() {
+                Some(packet) => packet,
+                None => {
+                    match try_ready!(self.transport.poll()) {
+                        Some(packet) => packet,
+                        None => return Ok(Async::Ready(None)),
+                    }
+                }
+            };
+            match ServerPacket::new(packet.data.clone()) {
+                // Ignore kpi and irc packets
+                Ok(ServerPacket::Kpi(_)) |
+                Ok(ServerPacket::Irc(_)) => {}
+                Ok(packet) => {
+                    trace!(self.logger, " received packet"; "packet" => format!("{:?}", packet));
+                    return Ok(Async::Ready(Some(packet)));
+                }
+                Err(err) => self.quarantine(&err, &packet.data),
+            }
+        }
+    }
+}
+
+impl Sink for AsyncConnection {
+    type SinkItem = ClientPacket;
+    type SinkError = Error;
+
+    fn start_send(&mut self, packet: ClientPacket) -> StartSend<ClientPacket, Error> {
+        let raw = SmartFoxPacket(packet.to_raw_data(&self.config));
+        match self.transport.start_send(raw)? {
+            AsyncSink::Ready => {
+                debug!(self.logger, "     send packet"; "packet" => format!("{:?}", packet));
+                Ok(AsyncSink::Ready)
+            }
+            AsyncSink::NotReady(_) => Ok(AsyncSink::NotReady(packet)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        Ok(self.transport.poll_complete()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use slog::Discard;
+    use tokio_core::reactor::Core;
+    use connection::LOCAL_CONFIG;
+
+    fn codec() -> SmartFoxClientCodec {
+        SmartFoxClientCodec::new(DeadLetters::new(), Logger::root(Discard, o!()))
+    }
+
+    fn read_frame<R: BufRead>(reader: &mut R) -> String {
+        let mut data = Vec::new();
+        reader.read_until(0, &mut data).unwrap();
+        data.pop();
+        String::from_utf8(data).unwrap()
+    }
+
+    /// Connect to a server answering the version check with `ver_chk` and not the login
+    fn connect_with(ver_chk: &'static [u8]) -> ::error::Result<AsyncConnection> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            assert!(read_frame(&mut reader).contains("verChk"));
+            if ver_chk.is_empty() {
+                return writer;
+            }
+            writer.write_all(ver_chk).unwrap();
+            assert!(read_frame(&mut reader).contains("<login z='EmpireEx_11'>"));
+            assert!(read_frame(&mut reader).contains("%lli%"));
+            writer.write_all(b"%xt%lli%1%0%\0%xt%gbd%1%0%{}%\0").unwrap();
+            writer
+        });
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let con = core.run(AsyncConnection::connect(
+            &addr,
+            LOCAL_CONFIG.clone(),
+            "tester",
+            "secret",
+            &handle,
+            Logger::root(Discard, o!()),
+        ));
+        server.join().unwrap();
+        let con = con?;
+        match core.run(con.into_future()) {
+            Ok((Some(ServerPacket::Gbd(_)), con)) => Ok(con),
+            Ok((packet, _)) => panic!("expected gbd, got {:?}", packet),
+            Err((err, _)) => Err(err),
+        }
+    }
+
+    #[test]
+    fn login_not_answered() {
+        connect_with(b"<msg t='sys'><body action='apiOK' r='0'></body></msg>\0").unwrap();
+    }
+
+    #[test]
+    fn version_check_not_answered() {
+        match connect_with(b"") {
+            Err(Error(ErrorKind::Timeout, _)) => {}
+            Err(err) => panic!("{}", err),
+            Ok(_) => panic!("connected without version check"),
+        }
+    }
+
+    #[test]
+    fn decode_packets() {
+        let mut buf = BytesMut::from(&b"%xt%nfo%1%0%{}%\0%xt%gaa"[..]);
+        assert_eq!(
+            codec().decode(&mut buf).unwrap().unwrap().data,
+            "%xt%nfo%1%0%{}%"
+        );
+        assert!(codec().decode(&mut buf).unwrap().is_none());
+        assert_eq!(&buf[..], b"%xt%gaa");
+    }
+
+    #[test]
+    fn skip_invalid_utf8() {
+        let mut codec = codec();
+        let mut buf = BytesMut::from(&b"%xt%\xff%\0%xt%nfo%1%0%{}%\0"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap().data, "%xt%nfo%1%0%{}%");
+        assert_eq!(codec.dead_letters.letters()[0].data, b"%xt%\xff%".to_vec());
+    }
+
+    #[test]
+    fn encode_packet() {
+        let mut buf = BytesMut::new();
+        codec()
+            .encode(SmartFoxPacket("%xt%EmpireEx_11%gdi%1%{}%"), &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..], &b"%xt%EmpireEx_11%gdi%1%{}%\0"[..]);
+    }
+}
@@ -1,68 +1,46 @@
-extern crate regex;
+#[macro_use]
+extern crate slog;
+extern crate slog_term;
 
-extern crate futures;
-extern crate tokio_core;
-extern crate tokio_service;
+extern crate gge;
 
-extern crate smartfox;
+use std::env;
+use std::sync::Mutex;
 
-use futures::{future, Future, BoxFuture, Stream, Sink};
-use tokio_core::reactor::Core;
-use tokio_core::io::{copy, Io, Framed};
-use tokio_core::net::TcpListener;
-use tokio_service::{Service, NewService};
-
-use smartfox::{SmartFoxCodec, SmartFoxService, Delegate};
+use gge::dummy_server::DummyServer;
+use gge::scenario::{Scenario, Responder};
+use gge::world_gen::{GeneratedWorld, WorldGenConfig};
 
+/// Usage: dummy_gge_server [scenario.json | --generate <seed>] [address]
 fn main() {
-    let mut core = Core::new().unwrap();
-    let handle = core.handle();
-
-    let listener = TcpListener::bind(&"127.0.0.1:8081".parse().unwrap(), &handle).unwrap();
-
-    let connections = listener.incoming();
-    let server = connections.for_each(move |(socket, _peer_addr)| {
-        let (writer, reader) = socket.framed(SmartFoxCodec).split();
-        let service = (|| Ok(SmartFoxService::new(ServerDelegate { loggedin: false })))
-            .new_service()?;
-
-        let responses = reader.and_then(move |req| service.call(req));
-        let server = writer.send_all(responses).then(|_| Ok(()));
-        handle.spawn(server);
-
-        Ok(())
-    });
-
-    core.run(server).unwrap();
-}
-
-
-struct ServerDelegate {
-    loggedin: bool,
-}
-
-impl Delegate for ServerDelegate {
-    fn login(&mut self, _room: &str, _un: &str, _pw: &str) -> Vec<smartfox::packet::Packet> {
-        //const RESPONSE: &'static str = include_str!("../../raw_data.bin");
-        //let res = RESPONSE.split('\n').map(|pkt|pkt.parse::<smartfox::packet::Packet>().unwrap()).collect::<Vec<_>>();
-        //println!("{:?}\n{:?}", res[0], res[10]);
-        //res
-        Vec::new()
-    }
-
-    fn request(&mut self, packet: smartfox::packet::Packet) -> Vec<smartfox::packet::Packet> {
-        const RESPONSE: &'static str = include_str!("../../raw_data.bin");
-        let res = RESPONSE
-            .split('\n')
-            .map(|pkt| pkt.parse::<smartfox::packet::Packet>().unwrap())
-            .collect::<Vec<_>>();
-        println!("{:?}", packet);
-        if !self.loggedin {
-            println!("{:#?}", res);
-            self.loggedin = true;
-            res
-        } else {
-            Vec::new()
+    let logger = slog::Logger::root(
+        slog::Fuse::new(Mutex::new(
+            slog::LevelFilter::new(slog_term::term_compact(), slog::Level::Trace),
+        )),
+        o!(),
+    );
+
+    let mut args = env::args().skip(1);
+    let responder: Box<Responder> = match args.next() {
+        Some(ref arg) if arg == "--generate" => {
+            let seed = args.next()
+                .and_then(|seed| seed.parse().ok())
+                .expect("Missing seed after --generate");
+            let world = GeneratedWorld::generate(&WorldGenConfig {
+                seed: seed,
+                ..WorldGenConfig::default()
+            });
+            info!(logger, "generated world";
+                "players" => world.players.len(),
+                "castles" => world.castles.len());
+            Box::new(world)
         }
-    }
+        Some(file) => Box::new(Scenario::load(&file).expect("Cant load scenario")),
+        None => Box::new(Scenario::default()),
+    };
+    let address = args.next().unwrap_or_else(|| "127.0.0.1:8081".to_string());
+
+    DummyServer::bind(&*address, responder, logger)
+        .expect("Cant start dummy server")
+        .wait();
 }
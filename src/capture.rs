@@ -0,0 +1,338 @@
+use std::fs::File;
+use std::path::Path;
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::time::{Duration, Instant};
+use std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
+
+use error::{Error, ErrorKind, Result, ResultExt};
+use smartfox::Transport;
+
+/// First bytes of every capture file
+const MAGIC: &'static [u8] = b"GGECAP01";
+
+/// Direction of a captured packet
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Direction {
+    /// Send by the client
+    Send,
+    /// Received from the server
+    Recv,
+}
+
+impl Direction {
+    fn to_byte(self) -> u8 {
+        match self {
+            Direction::Send => 0,
+            Direction::Recv => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Direction::Send),
+            1 => Some(Direction::Recv),
+            _ => None,
+        }
+    }
+}
+
+/// A captured SmartFoxServer packet
+///
+/// Stored as `[direction u8][time in ms u64][length u32][data]`, numbers are little endian.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    /// Direction of the packet
+    pub direction: Direction,
+    /// Time since the start of the capture
+    pub time: Duration,
+    /// Packet data without the zero terminator
+    pub data: Vec<u8>,
+}
+
+/// Writes frames to a capture
+pub struct CaptureWriter<W: Write> {
+    writer: W,
+    start: Instant,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Start a new capture
+    pub fn new(mut writer: W) -> Result<Self> {
+        writer.write_all(MAGIC).chain_err(
+            || "Cant write capture header",
+        )?;
+        Ok(CaptureWriter {
+            writer: writer,
+            start: Instant::now(),
+        })
+    }
+
+    /// Write a frame
+    pub fn write_frame(&mut self, frame: &Frame) -> Result<()> {
+        let millis = frame.time.as_secs() * 1000 +
+            u64::from(frame.time.subsec_nanos() / 1_000_000);
+        let mut header = Vec::with_capacity(13);
+        header.push(frame.direction.to_byte());
+        header.extend_from_slice(&u64_to_le(millis));
+        header.extend_from_slice(&u64_to_le(frame.data.len() as u64)[..4]);
+        self.writer
+            .write_all(&header)
+            .and_then(|()| self.writer.write_all(&frame.data))
+            .chain_err(|| "Cant write capture frame")
+    }
+
+    /// Write a packet which is send or received now
+    pub fn record(&mut self, direction: Direction, data: &[u8]) -> Result<()> {
+        let frame = Frame {
+            direction: direction,
+            time: self.start.elapsed(),
+            data: data.to_vec(),
+        };
+        self.write_frame(&frame)
+    }
+
+    /// Flush the underlying writer
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush().chain_err(|| "Cant flush capture")
+    }
+
+    /// Get the underlying writer
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Reads the frames of a capture
+pub struct CaptureReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Open a capture, fails when it doesn't start with the capture header
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic).chain_err(
+            || "Cant read capture header",
+        )?;
+        if magic != MAGIC {
+            return Err(ErrorKind::InvalidCapture("not a capture file".into()).into());
+        }
+        Ok(CaptureReader { reader: reader })
+    }
+
+    /// Read the next frame, None at the end of the capture
+    pub fn read_frame(&mut self) -> Result<Option<Frame>> {
+        let mut direction = [0; 1];
+        match self.reader.read(&mut direction) {
+            Ok(0) => return Ok(None),
+            Ok(_) => {}
+            Err(err) => return Err(err).chain_err(|| "Cant read capture frame"),
+        }
+        let direction = Direction::from_byte(direction[0]).ok_or_else(|| {
+            ErrorKind::InvalidCapture(format!("unknown direction {}", direction[0]).into())
+        })?;
+
+        let mut header = [0; 12];
+        let mut data = Vec::new();
+        self.reader
+            .read_exact(&mut header)
+            .and_then(|()| {
+                let len = u64_from_le(&header[8..12]) as usize;
+                data.resize(len, 0);
+                self.reader.read_exact(&mut data)
+            })
+            .map_err(|err| -> Error {
+                if err.kind() == io::ErrorKind::UnexpectedEof {
+                    ErrorKind::InvalidCapture("truncated frame".into()).into()
+                } else {
+                    err.into()
+                }
+            })?;
+
+        Ok(Some(Frame {
+            direction: direction,
+            time: Duration::from_millis(u64_from_le(&header[0..8])),
+            data: data,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Result<Frame>> {
+        match self.read_frame() {
+            Ok(frame) => frame.map(Ok),
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+/// Capture file shared by all connections of a session
+#[derive(Clone)]
+pub struct Recorder {
+    writer: Arc<Mutex<CaptureWriter<BufWriter<File>>>>,
+}
+
+impl Recorder {
+    /// Create or truncate the capture file
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::create(path).chain_err(|| "Cant create capture file")?;
+        Ok(Recorder { writer: Arc::new(Mutex::new(CaptureWriter::new(BufWriter::new(file))?)) })
+    }
+
+    /// Record a packet, it is flushed immediately so a crash doesn't lose it
+    pub fn record(&self, direction: Direction, data: &[u8]) -> Result<()> {
+        let mut writer = self.writer.lock().expect("Cant lock capture");
+        writer.record(direction, data)?;
+        writer.flush()
+    }
+}
+
+/// Transport replaying the received packets of a capture
+///
+/// Everything written to it is discarded, so the client can send requests as usual. The
+/// packets are returned in the captured order without delays. When all of them are read,
+/// reads fail with `io::ErrorKind::UnexpectedEof`, which `SmartFoxClient` turns into
+/// `ErrorKind::CaptureEnded`.
+#[derive(Clone)]
+pub struct Replay {
+    data: Arc<Mutex<VecDeque<u8>>>,
+}
+
+impl Replay {
+    /// Replay a capture file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path).chain_err(|| "Cant open capture file")?;
+        let frames = CaptureReader::new(BufReader::new(file))?.collect::<Result<Vec<_>>>()?;
+        Ok(Replay::from_frames(frames))
+    }
+
+    /// Replay the received frames
+    pub fn from_frames<I: IntoIterator<Item = Frame>>(frames: I) -> Self {
+        let mut data = VecDeque::new();
+        for frame in frames {
+            if frame.direction == Direction::Recv {
+                data.extend(frame.data);
+                data.push_back(0);
+            }
+        }
+        Replay { data: Arc::new(Mutex::new(data)) }
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut data = self.data.lock().expect("Cant lock replay");
+        if data.is_empty() && !buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "end of capture",
+            ));
+        }
+        let len = ::std::cmp::min(buf.len(), data.len());
+        for (byte, data) in buf.iter_mut().zip(data.drain(..len)) {
+            *byte = data;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Replay {
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> io::Result<Box<Transport>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+fn u64_to_le(value: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (value >> (i * 8)) as u8;
+    }
+    bytes
+}
+
+fn u64_from_le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |value, &byte| value << 8 | u64::from(byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::{Logger, Discard};
+    use connection::{Connection, LOCAL_CONFIG};
+    use packet::ServerPacket;
+
+    fn frame(direction: Direction, data: &str) -> Frame {
+        Frame {
+            direction: direction,
+            time: Duration::from_millis(1500),
+            data: data.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn write_and_read_frames() {
+        let frames = vec![
+            frame(Direction::Send, "%xt%EmpireEx_11%gdi%1%{}%"),
+            frame(Direction::Recv, "%xt%gdi%1%0%{}%"),
+        ];
+        let mut writer = CaptureWriter::new(Vec::new()).unwrap();
+        for frame in &frames {
+            writer.write_frame(frame).unwrap();
+        }
+        let data = writer.into_inner();
+        assert_eq!(&data[..8], MAGIC);
+
+        let read = CaptureReader::new(&data[..])
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(read, frames);
+
+        match CaptureReader::new(&data[..data.len() - 1])
+            .unwrap()
+            .collect::<Result<Vec<_>>>() {
+            Err(Error(ErrorKind::InvalidCapture(_), _)) => {}
+            result => panic!("{:?}", result.map(|_| ())),
+        }
+        assert!(CaptureReader::new(&b"%xt%gdi%1%0%{}%"[..]).is_err());
+    }
+
+    #[test]
+    fn replay_connection() {
+        let replay = Replay::from_frames(vec![
+            frame(
+                Direction::Send,
+                "<msg t='sys'><body action='verChk' r='0'><ver v='166' /></body></msg>",
+            ),
+            frame(Direction::Recv, "<msg t='sys'><body action='apiOK' r='0'></body></msg>"),
+            frame(Direction::Recv, "<msg t='sys'><body action='logOK' r='0'></body></msg>"),
+            frame(Direction::Recv, "%xt%lli%1%0%"),
+            frame(Direction::Recv, r#"%xt%gbd%1%0%{"gpi":{"UID":0}}%"#),
+        ]);
+        let mut con = Connection::replay(replay, LOCAL_CONFIG.clone(), Logger::root(Discard, o!()))
+            .unwrap();
+        match con.next_response().unwrap() {
+            Some(ServerPacket::Gbd(_)) => {}
+            packet => panic!("{:?}", packet),
+        }
+        assert!(con.next_response().unwrap().is_none());
+    }
+}
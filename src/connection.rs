@@ -1,101 +1,444 @@
 use std::str;
 use std::net::{TcpStream, SocketAddr};
+use std::time::{Duration, Instant};
 
 use slog::*;
 
-use error::{Result, ResultExt};
-use smartfox::{SmartFoxClient, SmartFoxPacket};
-use packet::{ServerPacket, ClientPacket};
+use error::{Error, ErrorKind, Result, ResultExt};
+use smartfox::{SmartFoxClient, SmartFoxPacket, Transport};
+use capture::{Recorder, Replay};
+use packet::{ServerPacket, ClientPacket, LoginStatus};
+use dead_letter::DeadLetters;
+use correlation::{Expected, Outstanding};
+use pacer::Pacer;
+
+/// Default time `Connection::next_response` waits for all answers
+pub const DEFAULT_DEADLINE: u64 = 60;
+
+/// Time without packets after which `Connection::read_packets` stops
+const READ_PACKETS_TIMEOUT: u64 = 2;
 
 /// Goodgame empire connection
 pub struct Connection {
     smartfox: SmartFoxClient,
+    config: ConnectionConfig,
+    outstanding: Outstanding,
+    deadline: Duration,
+    /// When the last packet was send or received while answers were outstanding
+    last_activity: Option<Instant>,
+    keepalive: Option<Duration>,
+    last_send: Instant,
+    pacer: Option<Pacer>,
     logger: Logger,
 }
 
+/// Something packets can be send to
+pub trait PacketSender {
+    /// Send gge packet
+    fn send_packet(&mut self, packet: ClientPacket) -> Result<()>;
+}
+
+/// Settings that differ between the national servers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectionConfig {
+    /// SmartFoxServer zone, also used as room for all game packets
+    pub zone: String,
+    /// Client language
+    pub language: String,
+    /// Build timestamp of the client, used as SmartFoxServer password
+    pub client_timestamp: String,
+    /// `CONM` login value
+    pub conm: u64,
+    /// `RTM` login value
+    pub rtm: u64,
+    /// `AID` login value
+    pub aid: String,
+    /// `REF` login value
+    pub referer: String,
+}
+
+impl ConnectionConfig {
+    /// The SmartFoxServer login password
+    pub fn smartfox_password(&self) -> String {
+        format!("{}%{}%", self.client_timestamp, self.language)
+    }
+}
+
+impl Default for ConnectionConfig {
+    /// The settings of the Dutch server
+    fn default() -> Self {
+        ConnectionConfig {
+            zone: "EmpireEx_11".to_string(),
+            language: "nl".to_string(),
+            client_timestamp: "1455712286016".to_string(), // 02/17/2016 @ 12:31pm (UTC)
+            conm: 182,
+            rtm: 32,
+            aid: "1433061122034798333".to_string(),
+            referer: "http://empire.goodgamestudios.com".to_string(),
+        }
+    }
+}
+
+// Only the Dutch server has a preset, its address, zone and login values are the ones the
+// importer always used. The zones of the other national servers aren't known, they can be added
+// with a servers file, see `servers::ServerRegistry::load`.
 lazy_static!{
     /// The Dutch server (37.48.88.129)
     pub static ref DUTCH_SERVER: SocketAddr = "37.48.88.129:80".parse().unwrap();
+    /// Settings of the Dutch server
+    pub static ref DUTCH_CONFIG: ConnectionConfig = ConnectionConfig::default();
     /// Local server (127.0.0.1:8081)
     pub static ref LOCAL_SERVER: SocketAddr = "127.0.0.1:8081".parse().unwrap();
+    /// Settings of the local server, it mimics the Dutch server
+    pub static ref LOCAL_CONFIG: ConnectionConfig = DUTCH_CONFIG.clone();
 }
 
 impl Connection {
     /// Create a new connection
     ///
     /// ## SmartFoxServer settings
-    /// * room: "EmpireEx_11"
+    /// * room: `config.zone`, for example "EmpireEx_11"
     /// * username: ""
-    /// * password: "1455712286016%nl%" `02/17/2016 @ 12:31pm (UTC)` unix timestamp with millisecond precision
+    /// * password: `config.smartfox_password()`, for example "1455712286016%nl%"
+    ///
     /// # Sends and receives
     /// ## login
     ///
     /// ```xml
-    /// send: %xt%EmpireEx_11%lli%1%{"CONM":413,"KID":"","DID":"","ID":0,"PW":"<#password#>","AID":"1456064275209394654","NOM":"<#username#>","RTM":129,"LANG":"nl"}%
+    /// send: %xt%EmpireEx_11%lli%1%{"CONM":182,"RTM":32,"ID":0,"PW":"<#password#>","NOM":"<#username#>","LANG":"nl",...}%
+    /// recv: %xt%lli%1%<#status#>%
     /// ```
-    pub fn new(server: SocketAddr, un: &str, pw: &str, logger: Logger) -> Result<Self> {
-        let stream = try!(TcpStream::connect(server).chain_err(
-            || "Can't connect to server",
-        ));
+    ///
+    /// Returns `ErrorKind::LoginFailed` when the status is not 0.
+    ///
+    /// After the login the server sends a gbd packet, `next_response` waits for it.
+    pub fn new(
+        server: SocketAddr,
+        config: ConnectionConfig,
+        un: &str,
+        pw: &str,
+        logger: Logger,
+    ) -> Result<Self> {
+        Connection::recording(server, config, un, pw, None, logger)
+    }
+
+    /// Create a new connection which writes all packets to `recorder`, see `new`
+    pub fn recording(
+        server: SocketAddr,
+        config: ConnectionConfig,
+        un: &str,
+        pw: &str,
+        recorder: Option<Recorder>,
+        logger: Logger,
+    ) -> Result<Self> {
+        let stream = TcpStream::connect(server).map_err(|err| {
+            Error::with_chain(err, ErrorKind::ConnectionLost("Can't connect to server".into()))
+        })?;
         try!(
             stream
-                .set_read_timeout(Some(::std::time::Duration::new(2, 0)))
+                .set_read_timeout(Some(Duration::new(READ_PACKETS_TIMEOUT, 0)))
                 .chain_err(|| "Can't set server connection timeout")
         );
+        Connection::with_transport(Box::new(stream), config, un, pw, recorder, logger)
+    }
 
-        let smartfox = SmartFoxClient::new(
+    /// Create a connection which replays a capture instead of talking to a server
+    ///
+    /// The send packets are discarded. When all packets are replayed `next_response` returns
+    /// None.
+    pub fn replay(replay: Replay, config: ConnectionConfig, logger: Logger) -> Result<Self> {
+        Connection::with_transport(Box::new(replay), config, "", "", None, logger)
+    }
+
+    fn with_transport(
+        stream: Box<Transport>,
+        config: ConnectionConfig,
+        un: &str,
+        pw: &str,
+        recorder: Option<Recorder>,
+        logger: Logger,
+    ) -> Result<Self> {
+        let smartfox = SmartFoxClient::with_transport(
             stream,
-            "EmpireEx_11", // room
+            &config.zone, // room
             "",
-            "1455712286016%nl%", // 02/17/2016 @ 12:31pm (UTC) unix timestamp with millisecond precision
+            &config.smartfox_password(),
+            recorder,
             logger.clone(),
         )?;
         let mut con = Connection {
             smartfox: smartfox,
+            config: config,
+            outstanding: Outstanding::new(),
+            deadline: Duration::new(DEFAULT_DEADLINE, 0),
+            last_activity: None,
+            keepalive: None,
+            last_send: Instant::now(),
+            pacer: None,
             logger: logger,
         };
 
-        let login_code = r##"%xt%EmpireEx_11%lli%1%{"RTM":32,"FID":null,"ID":0,"PW":"{pw}","FTK":null,"REF":"http://empire.goodgamestudios.com","FAID":null,"KID":"","LANG":"nl","NOM":"{un}","AID":"1433061122034798333","CONM":182,"DID":""}%"##.to_string().replace("{pw}", pw).replace("{un}", un);
-
-        con.smartfox.send_packet(SmartFoxPacket(login_code))?;
+        con.send_packet(ClientPacket::Lli {
+            username: un.to_string(),
+            password: pw.to_string(),
+        })?;
+        con.wait_for_login()?;
+        con.outstanding.expect("gbd");
 
         Ok(con)
     }
 
+    /// Wait for the lli answer, other packets are kept for `read_packets`
+    fn wait_for_login(&mut self) -> Result<()> {
+        let mut skipped = Vec::new();
+        let result = loop {
+            let packet = match self.smartfox.recv_packet() {
+                Ok(packet) => packet,
+                Err(err) => break Err(err),
+            };
+            match ServerPacket::new(packet.data.clone()) {
+                Ok(ServerPacket::Lli(LoginStatus::Ok)) => {
+                    self.outstanding.received(&ServerPacket::Lli(LoginStatus::Ok));
+                    break Ok(());
+                }
+                Ok(ServerPacket::Lli(status)) => {
+                    break Err(ErrorKind::LoginFailed(status.code()).into())
+                }
+                _ => skipped.push(packet),
+            }
+        };
+        self.smartfox.unread(skipped);
+        if result.is_ok() {
+            debug!(self.logger, "logged in");
+        }
+        result
+    }
+
     // clean connection
 
+    /// The settings of the server this connection belongs to
+    pub fn config(&self) -> &ConnectionConfig {
+        &self.config
+    }
+
+    /// Set the maximum time `next_response` waits for the next answer
+    ///
+    /// The time is counted from the last send request or received packet, so a long burst of
+    /// paced requests doesn't use it up.
+    pub fn set_deadline(&mut self, deadline: Duration) {
+        self.deadline = deadline;
+    }
+
+    /// Send a keepalive when nothing was send for `interval`, None disables keepalives
+    ///
+    /// Keepalives are send by `keepalive` and while waiting in `next_response`.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>) {
+        self.keepalive = interval;
+    }
+
+    /// Send a keepalive when it is time for one
+    pub fn keepalive(&mut self) -> Result<()> {
+        if let Some(interval) = self.keepalive {
+            if self.last_send.elapsed() >= interval {
+                trace!(self.logger, "send keepalive");
+                self.send_packet(ClientPacket::Pin)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Limit the rate at which requests are send, None sends them as fast as possible
+    ///
+    /// Keepalives are not limited.
+    pub fn set_pacer(&mut self, pacer: Option<Pacer>) {
+        self.pacer = pacer;
+    }
+
+    /// The answers the server still has to send
+    pub fn outstanding(&self) -> &Outstanding {
+        &self.outstanding
+    }
+
     /// Send gge packet
     pub fn send_packet(&mut self, packet: ClientPacket) -> Result<()> {
+        if let Some(ref mut pacer) = self.pacer {
+            if packet.reply_command().is_some() {
+                pacer.wait();
+            }
+        }
         self.smartfox.send_packet(
-            SmartFoxPacket(packet.to_raw_data()),
+            SmartFoxPacket(packet.to_raw_data(&self.config)),
         )?;
         debug!(self.logger, "     send packet"; "packet" => format!("{:?}", packet));
+        self.last_send = Instant::now();
+        self.last_activity = Some(self.last_send);
+        self.outstanding.sent(&packet);
         Ok(())
     }
 
-    /// Read gge packets
+    /// Read the next answer of the send requests
+    ///
+    /// Returns None when all answers arrived, when nothing was send or received for the deadline
+    /// or when a replayed capture ended. In the last two cases the missing answers are forgotten.
+    ///
+    /// Answers that are quarantined as malformed stay missing until the deadline. Map answers
+    /// without area get the area of the request they were matched with.
     ///
     /// Ignores kpi and irc packets
-    pub fn read_packets(&mut self, logger: Logger) -> Result<Box<Iterator<Item = ServerPacket>>> {
+    pub fn next_response(&mut self) -> Result<Option<ServerPacket>> {
+        loop {
+            if self.outstanding.is_empty() {
+                self.last_activity = None;
+                return Ok(None);
+            }
+
+            let last_activity = *self.last_activity.get_or_insert_with(Instant::now);
+            let elapsed = last_activity.elapsed();
+            if elapsed >= self.deadline {
+                let missing = self.outstanding.clear();
+                warn!(self.logger, "deadline passed before all answers arrived";
+                    "missing" => missing.len());
+                self.last_activity = None;
+                return Ok(None);
+            }
+            self.keepalive()?;
+            let mut timeout = self.deadline - elapsed;
+            if let Some(interval) = self.keepalive {
+                let idle = self.last_send.elapsed();
+                if idle < interval && interval - idle < timeout {
+                    timeout = interval - idle;
+                }
+            }
+            self.smartfox
+                .stream
+                .set_read_timeout(Some(timeout))
+                .chain_err(|| "Can't set server connection timeout")?;
+
+            let mut packet = match self.smartfox.recv_packet() {
+                Ok(packet) => {
+                    self.last_activity = Some(Instant::now());
+                    match ServerPacket::new(packet.data.clone()) {
+                        Ok(parsed) => parsed,
+                        Err(err) => {
+                            self.quarantine(&err, &packet.data);
+                            continue;
+                        }
+                    }
+                }
+                Err(ref err) if is_timeout(err) => continue,
+                Err(Error(ErrorKind::CaptureEnded, _)) => {
+                    let missing = self.outstanding.clear();
+                    debug!(self.logger, "capture ended"; "missing" => missing.len());
+                    self.last_activity = None;
+                    return Ok(None);
+                }
+                Err(err) => return Err(err),
+            };
+            match packet {
+                // Ignore kpi and irc packets
+                ServerPacket::Kpi(_) |
+                ServerPacket::Irc(_) => continue,
+                _ => {}
+            }
+            match self.outstanding.received(&packet) {
+                Some(Expected { request: Some(ClientPacket::Gaa(ref request)), .. }) => {
+                    // Map answers that don't echo their area answer the oldest map request
+                    if let ServerPacket::Gaa(ref mut gaa) = packet {
+                        if gaa.data.area.is_none() {
+                            gaa.data.area = Some(request.area());
+                        }
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    debug!(self.logger, "received unrequested packet";
+                        "command" => packet.command());
+                }
+            }
+            trace!(self.logger, " received packet"; "packet" => format!("{:?}", packet));
+            return Ok(Some(packet));
+        }
+    }
+
+    /// Read gge packets
+    ///
+    /// Stops when no packet arrived for two seconds. Ignores kpi and irc packets.
+    /// Malformed packets are returned as errors and quarantined to the dead letters, the
+    /// iterator continues after them.
+    pub fn read_packets(
+        &mut self,
+        logger: Logger,
+    ) -> Result<Box<Iterator<Item = Result<ServerPacket>>>> {
+        self.smartfox
+            .stream
+            .set_read_timeout(Some(Duration::new(READ_PACKETS_TIMEOUT, 0)))
+            .chain_err(|| "Can't set server connection timeout")?;
+        let dead_letters = self.smartfox.dead_letters().clone();
+        let trace_logger = logger.clone();
         let data = self.smartfox
             .read_packets(logger.clone())
             .chain_err(|| "Couldnt read packets")?
-            .map(|p| {
-                ServerPacket::new(p.data).expect("Received invalid packet")
+            .map(move |packet| {
+                let packet = packet?;
+                ServerPacket::new(packet.data.clone()).map_err(|err| {
+                    if let Err(err) = dead_letters.push(&err.to_string(), packet.data.as_bytes()) {
+                        error!(logger, "Couldnt quarantine packet"; "error" => err.to_string());
+                    }
+                    warn!(logger, "quarantined packet"; "error" => err.to_string());
+                    err
+                })
             })
             .filter(|packet| {
                 // Ignore kpi and irc packets
                 match *packet {
-                    ServerPacket::Kpi(_) |
-                    ServerPacket::Irc(_) => false,
+                    Ok(ServerPacket::Kpi(_)) |
+                    Ok(ServerPacket::Irc(_)) => false,
                     _ => true,
                 }
             })
             .map(move |packet| {
-                trace!(logger, " received packet"; "packet" => format!("{:?}", packet));
+                if let Ok(ref packet) = packet {
+                    trace!(trace_logger, " received packet"; "packet" => format!("{:?}", packet));
+                }
                 packet
             });
 
         Ok(Box::new(data))
     }
+
+    /// Where malformed packets are quarantined
+    pub fn dead_letters(&self) -> &DeadLetters {
+        self.smartfox.dead_letters()
+    }
+
+    /// Quarantine malformed packets to another log
+    pub fn set_dead_letters(&mut self, dead_letters: DeadLetters) {
+        self.smartfox.set_dead_letters(dead_letters);
+    }
+
+    /// Quarantine a packet that couldn't be parsed
+    ///
+    /// The request it answers stays outstanding, like for frames that aren't utf8. Which request
+    /// that is can't be told for sure from a broken packet, so `next_response` forgets it at the
+    /// deadline and a map scan requests the tile again.
+    fn quarantine(&mut self, err: &Error, data: &str) {
+        warn!(self.logger, "quarantined packet"; "error" => err.to_string());
+        if let Err(err) = self.smartfox.dead_letters().push(&err.to_string(), data.as_bytes()) {
+            error!(self.logger, "Couldnt quarantine packet"; "error" => err.to_string());
+        }
+    }
+}
+
+fn is_timeout(err: &Error) -> bool {
+    match *err.kind() {
+        ErrorKind::Timeout => true,
+        _ => false,
+    }
+}
+
+impl PacketSender for Connection {
+    fn send_packet(&mut self, packet: ClientPacket) -> Result<()> {
+        Connection::send_packet(self, packet)
+    }
 }
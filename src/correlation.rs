@@ -0,0 +1,142 @@
+use std::collections::VecDeque;
+
+use packet::{ServerPacket, ClientPacket};
+
+/// A packet the server is expected to send
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expected {
+    /// Command of the answer, for example "gaa"
+    pub command: &'static str,
+    /// The request it answers, None for packets the server sends by itself like gbd
+    pub request: Option<ClientPacket>,
+}
+
+/// Keeps track of the answers the server still has to send
+#[derive(Debug, Clone, Default)]
+pub struct Outstanding {
+    expected: VecDeque<Expected>,
+}
+
+impl Outstanding {
+    /// Create an empty tracker
+    pub fn new() -> Self {
+        Outstanding::default()
+    }
+
+    /// Register a send request
+    pub fn sent(&mut self, request: &ClientPacket) {
+        if let Some(command) = request.reply_command() {
+            self.expected.push_back(Expected {
+                command: command,
+                request: Some(request.clone()),
+            });
+        }
+    }
+
+    /// Expect a packet the server sends without request
+    pub fn expect(&mut self, command: &'static str) {
+        self.expected.push_back(Expected {
+            command: command,
+            request: None,
+        });
+    }
+
+    /// Register a received packet
+    ///
+    /// Returns what the packet answered, None when nothing was waiting for it.
+    /// Map answers are matched by area, other answers in the order the requests were send.
+    pub fn received(&mut self, packet: &ServerPacket) -> Option<Expected> {
+        let command = packet.command();
+        let area = packet.gaa_area();
+        let index = self.expected
+            .iter()
+            .position(|expected| {
+                expected.command == command &&
+                    match (area, &expected.request) {
+                        (Some(area), &Some(ClientPacket::Gaa(ref request))) => {
+                            request.area() == area
+                        }
+                        _ => true,
+                    }
+            })
+            .or_else(|| {
+                self.expected.iter().position(
+                    |expected| expected.command == command,
+                )
+            });
+        index.and_then(|index| self.expected.remove(index))
+    }
+
+    /// Is nothing outstanding?
+    pub fn is_empty(&self) -> bool {
+        self.expected.is_empty()
+    }
+
+    /// Amount of outstanding answers
+    pub fn len(&self) -> usize {
+        self.expected.len()
+    }
+
+    /// The requests which are not answered yet
+    pub fn requests(&self) -> Vec<ClientPacket> {
+        self.expected
+            .iter()
+            .filter_map(|expected| expected.request.clone())
+            .collect()
+    }
+
+    /// Forget all outstanding answers and return them
+    pub fn clear(&mut self) -> Vec<Expected> {
+        self.expected.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data::World;
+    use packet::GaaRequest;
+
+    fn gaa_request(x1: u64) -> ClientPacket {
+        ClientPacket::Gaa(GaaRequest {
+            world: World::Grass,
+            x1: x1,
+            y1: 0,
+            x2: x1 + 12,
+            y2: 12,
+        })
+    }
+
+    #[test]
+    fn match_answers() {
+        let mut outstanding = Outstanding::new();
+        outstanding.expect("gbd");
+        outstanding.sent(&gaa_request(0));
+        outstanding.sent(&gaa_request(13));
+        outstanding.sent(&ClientPacket::Gdi(42));
+        assert_eq!(outstanding.len(), 4);
+
+        let gaa = ServerPacket::new(
+            r#"%xt%gaa%1%0%{"KID":0,"AX1":13,"AY1":0,"AX2":25,"AY2":12,"OI":[],"AI":[]}%"#
+                .to_string(),
+        ).unwrap();
+        assert_eq!(
+            outstanding.received(&gaa).unwrap().request,
+            Some(gaa_request(13))
+        );
+
+        let gdi = ServerPacket::new("%xt%gdi%1%0%{}%".to_string()).unwrap();
+        assert_eq!(
+            outstanding.received(&gdi).unwrap().request,
+            Some(ClientPacket::Gdi(42))
+        );
+        assert_eq!(outstanding.received(&gdi), None);
+
+        let gbd = ServerPacket::new(r#"%xt%gbd%1%0%{"gpi":{"UID":0}}%"#.to_string()).unwrap();
+        assert_eq!(outstanding.received(&gbd).unwrap().request, None);
+
+        assert_eq!(outstanding.len(), 1);
+        assert_eq!(outstanding.clear()[0].request, Some(gaa_request(0)));
+        assert!(outstanding.is_empty());
+    }
+}
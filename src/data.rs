@@ -1,14 +1,11 @@
 use std::fmt;
-use std::collections::HashMap;
-use std::sync::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use serde::de::{Deserialize, Deserializer, Visitor};
 
-lazy_static!{
-    pub static ref DATAMGR: Mutex<DataMgr> = {
-        Mutex::new(DataMgr::new())
-    };
-}
+use map_scan::Area;
+use storage::{ImportRun, Storage};
 
 /// World
 #[derive(Debug, Hash, Eq, PartialEq, Copy, Clone, Serialize)]
@@ -25,6 +22,20 @@ pub enum World {
     SpecialEvent = 4,
 }
 
+impl World {
+    /// The world with the given kingdom id
+    pub fn from_number(number: u64) -> Option<World> {
+        match number {
+            0 => Some(World::Grass),
+            1 => Some(World::Sand),
+            2 => Some(World::Ice),
+            3 => Some(World::Fire),
+            4 => Some(World::SpecialEvent),
+            _ => None,
+        }
+    }
+}
+
 impl<'de> Deserialize<'de> for World {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -36,18 +47,9 @@ impl<'de> Deserialize<'de> for World {
             type Value = World;
 
             fn visit_u64<E: ::serde::de::Error>(self, v: u64) -> Result<Self::Value, E> {
-                match v {
-                    0 => Ok(World::Grass),
-                    1 => Ok(World::Sand),
-                    2 => Ok(World::Ice),
-                    3 => Ok(World::Fire),
-                    4 => Ok(World::SpecialEvent),
-                    _ => {
-                        Err(::serde::de::Error::custom(
-                            format_args!("Unrecognized world number {}", v),
-                        ))
-                    }
-                }
+                World::from_number(v).ok_or_else(|| {
+                    ::serde::de::Error::custom(format_args!("Unrecognized world number {}", v))
+                })
             }
 
             fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -59,8 +61,59 @@ impl<'de> Deserialize<'de> for World {
     }
 }
 
+/// Worlds in snapshot files
+///
+/// `World` is deserialized from the kingdom ids the server sends, but serialized by name. The
+/// snapshot types read the names back with `#[serde(with = "world_name")]`.
+mod world_name {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use serde::de::Error;
+
+    use data::World;
+
+    pub fn serialize<S: Serializer>(world: &World, serializer: S) -> Result<S::Ok, S::Error> {
+        world.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<World, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        match &*name {
+            "Fire" => Ok(World::Fire),
+            "Sand" => Ok(World::Sand),
+            "Grass" => Ok(World::Grass),
+            "Ice" => Ok(World::Ice),
+            "SpecialEvent" => Ok(World::SpecialEvent),
+            _ => Err(D::Error::custom(format_args!("Unrecognized world {}", name))),
+        }
+    }
+
+    /// The same for castles of which the world isn't known
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        use data::World;
+
+        pub fn serialize<S: Serializer>(
+            world: &Option<World>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            world.serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<World>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            #[derive(Deserialize)]
+            struct Name(#[serde(with = "::data::world_name")] World);
+
+            Ok(Option::<Name>::deserialize(deserializer)?.map(|name| name.0))
+        }
+    }
+}
+
 /// Castle data
-#[derive(Debug, Hash, Eq, PartialEq, Clone, Serialize)]
+#[derive(Debug, Hash, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Castle {
     /// Internal id
     pub id: u64,
@@ -73,11 +126,12 @@ pub struct Castle {
     /// Y position
     pub y: Option<u64>,
     /// World
+    #[serde(default, with = "world_name::option")]
     pub world: Option<World>,
 }
 
 /// User data
-#[derive(Debug, Hash, Eq, PartialEq, Clone, Serialize)]
+#[derive(Debug, Hash, Eq, PartialEq, Clone, Serialize, Deserialize)]
 pub struct User {
     /// Internal id
     pub id: u64,
@@ -85,6 +139,8 @@ pub struct User {
     pub username: Option<String>,
     /// Is it from your own alliance?
     pub own_alliance: bool,
+    /// Internal id of the alliance the user is member of
+    pub alliance_id: Option<u64>,
 }
 
 impl fmt::Display for User {
@@ -93,56 +149,141 @@ impl fmt::Display for User {
     }
 }
 
+/// Alliance data
+#[derive(Debug, Hash, Eq, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Alliance {
+    /// Internal id
+    pub id: u64,
+    /// Alliance name
+    pub name: Option<String>,
+    /// Internal ids of the members and their rank, when it is known. 0 is the leader.
+    pub members: BTreeMap<u64, Option<u64>>,
+}
+
+impl Alliance {
+    /// Create an alliance without members
+    pub fn new(id: u64, name: Option<String>) -> Self {
+        Alliance {
+            id: id,
+            name: name,
+            members: BTreeMap::new(),
+        }
+    }
+}
+
 /// Data manager
-#[derive(Debug, Serialize)]
+///
+/// Each import writes into its own instance, so several accounts or worlds can be imported in
+/// one process.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct DataMgr {
     /// List of castles
     pub castles: HashMap<u64, Castle>,
     pub users: HashMap<u64, User>,
+    /// List of alliances
+    pub alliances: HashMap<u64, Alliance>,
+    /// Changes of the castles, oldest first
+    pub changes: Vec<Change>,
+}
+
+/// A castle field value that changed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CastleField {
+    /// Internal owner id, the castle was conquered
+    Owner(u64),
+    /// Castle name
+    Name(String),
+    /// X position
+    X(u64),
+    /// Y position
+    Y(u64),
+    /// World
+    World(#[serde(with = "world_name")] World),
 }
 
-macro_rules! same{
-    ($a:expr, $b:expr, $field:ident) => {
-        if !$b.is_none(){
-            if let (Some(a), Some(b)) = ($a.$field, $b.unwrap().$field){
-                assert_eq!(a, b, "{:?}, {:?}", $a, $b);
+/// Change of a castle between two imported packets
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    /// Seconds since the unix epoch
+    pub time: u64,
+    /// Internal castle id
+    pub castle_id: u64,
+    /// Stored value
+    pub old: CastleField,
+    /// Value that replaced it
+    pub new: CastleField,
+    /// Command of the packet the new value came from, for example "gaa"
+    pub source: Option<String>,
+}
+
+macro_rules! merge{
+    ($self_:ident, $castle:expr, $old:expr, $field:ident, $variant:ident, $source:expr) => {
+        match ($castle.$field.clone(), $old.$field.clone()) {
+            (Some(new), Some(old)) => {
+                if new != old {
+                    $self_.changes.push(Change {
+                        time: now(),
+                        castle_id: $castle.id,
+                        old: CastleField::$variant(old),
+                        new: CastleField::$variant(new),
+                        source: $source.map(|source: &str| source.to_string()),
+                    });
+                }
             }
+            (None, old) => $castle.$field = old,
+            (Some(_), None) => {}
         }
     }
 }
 
+/// Seconds since the unix epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
 impl DataMgr {
     /// Create new data manager
     pub fn new() -> Self {
         DataMgr {
             castles: HashMap::new(),
             users: HashMap::new(),
+            alliances: HashMap::new(),
+            changes: Vec::new(),
         }
     }
 
     /// Add the data of the specified castle
     pub fn add_castle(&mut self, castle: Castle) -> Castle {
+        self.add_castle_from(castle, None)
+    }
+
+    /// Add the data of the specified castle, received in a packet of the `source` command
+    ///
+    /// Unknown fields are taken from the stored castle. Fields that differ from the stored
+    /// castle replace it and are recorded in `changes`.
+    pub fn add_castle_from(&mut self, castle: Castle, source: Option<&str>) -> Castle {
         let mut castle = castle;
-        let old_castle = self.castles.remove(&castle.id);
-
-        same!(castle.clone(), old_castle.clone(), owner_id);
-        same!(castle.clone(), old_castle.clone(), name);
-        same!(castle.clone(), old_castle.clone(), x);
-        same!(castle.clone(), old_castle.clone(), y);
-        same!(castle.clone(), old_castle.clone(), world);
-
-        match old_castle {
-            Some(old_castle) => {
-                castle.owner_id = castle.owner_id.or(old_castle.owner_id);
-                castle.name = castle.name.or(old_castle.name);
-                castle.x = castle.x.or(old_castle.x);
-                castle.y = castle.y.or(old_castle.y);
-                castle.world = castle.world.or(old_castle.world);
-            }
-            None => {}
+        if let Some(old_castle) = self.castles.remove(&castle.id) {
+            merge!(self, castle, old_castle, owner_id, Owner, source);
+            merge!(self, castle, old_castle, name, Name, source);
+            merge!(self, castle, old_castle, x, X, source);
+            merge!(self, castle, old_castle, y, Y, source);
+            merge!(self, castle, old_castle, world, World, source);
         }
         self.castles.insert(castle.id, castle.clone());
-        return castle;
+        castle
+    }
+
+    /// Changes of a castle, oldest first
+    pub fn changes_of(&self, castle_id: u64) -> Vec<&Change> {
+        self.changes
+            .iter()
+            .filter(|change| change.castle_id == castle_id)
+            .collect()
     }
 
     /// Add the name of the specified user
@@ -151,11 +292,92 @@ impl DataMgr {
             id: uid,
             username: Some(name.to_owned()),
             own_alliance: false,
+            alliance_id: None,
         });
+        if user.username.is_none() {
+            user.username = Some(name.to_owned());
+        }
         if own_alliance {
             user.own_alliance = true;
         }
     }
+
+    /// Add an alliance, the name is only replaced when a new one is given
+    pub fn add_alliance(&mut self, id: u64, name: Option<String>) -> &mut Alliance {
+        let alliance = self.alliances.entry(id).or_insert_with(
+            || Alliance::new(id, None),
+        );
+        if name.is_some() {
+            alliance.name = name;
+        }
+        alliance
+    }
+
+    /// Add a user to an alliance, removes it from its previous alliance
+    ///
+    /// A known rank is kept when `rank` is None.
+    pub fn add_member(&mut self, alliance_id: u64, uid: u64, rank: Option<u64>) {
+        let previous = {
+            let user = self.users.entry(uid).or_insert(User {
+                id: uid,
+                username: None,
+                own_alliance: false,
+                alliance_id: None,
+            });
+            ::std::mem::replace(&mut user.alliance_id, Some(alliance_id))
+        };
+        if let Some(previous) = previous {
+            if previous != alliance_id {
+                if let Some(alliance) = self.alliances.get_mut(&previous) {
+                    alliance.members.remove(&uid);
+                }
+            }
+        }
+        let member = self.add_alliance(alliance_id, None)
+            .members
+            .entry(uid)
+            .or_insert(None);
+        if rank.is_some() {
+            *member = rank;
+        }
+    }
+
+    /// The alliance of the owner of a castle
+    pub fn alliance_of_castle(&self, castle_id: u64) -> Option<&Alliance> {
+        let owner = self.castles.get(&castle_id)?.owner_id?;
+        let alliance = self.users.get(&owner)?.alliance_id?;
+        self.alliances.get(&alliance)
+    }
+
+    /// Alliances owning castles in an area of a world and the amount of castles they own there
+    pub fn alliances_in(&self, world: World, area: Area) -> Vec<(&Alliance, usize)> {
+        let mut counts = BTreeMap::new();
+        for castle in self.castles.values() {
+            let inside = match (castle.world, castle.x, castle.y) {
+                (Some(w), Some(x), Some(y)) => {
+                    w == world && area.x1 <= x && x <= area.x2 && area.y1 <= y && y <= area.y2
+                }
+                _ => false,
+            };
+            if inside {
+                if let Some(alliance) = self.alliance_of_castle(castle.id) {
+                    *counts.entry(alliance.id).or_insert(0) += 1;
+                }
+            }
+        }
+        counts
+            .into_iter()
+            .map(|(id, count)| (&self.alliances[&id], count))
+            .collect()
+    }
+
+    /// Store the imported data, returns the id of the run when the storage keeps them apart
+    pub fn flush<S>(&self, storage: &mut S, run: &ImportRun) -> ::error::Result<Option<i64>>
+    where
+        S: Storage + ?Sized,
+    {
+        storage.save(run, self)
+    }
 }
 
 #[cfg(test)]
@@ -201,7 +423,47 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
+    fn alliance_members() {
+        let mut data_mgr = DataMgr::new();
+        data_mgr.add_owner_name(1, "tester", true);
+        data_mgr.add_alliance(7, Some("Knights".to_string()));
+        data_mgr.add_member(7, 1, Some(3));
+        data_mgr.add_member(7, 1, None);
+        data_mgr.add_member(8, 2, None);
+        data_mgr.add_castle(Castle {
+            id: 42,
+            owner_id: Some(1),
+            name: None,
+            x: Some(10),
+            y: Some(10),
+            world: Some(World::Grass),
+        });
+
+        assert_eq!(data_mgr.users[&1].alliance_id, Some(7));
+        assert_eq!(data_mgr.alliances[&7].members[&1], Some(3));
+        assert_eq!(data_mgr.alliances[&8].name, None);
+        assert_eq!(data_mgr.users[&2].username, None);
+        assert_eq!(data_mgr.alliance_of_castle(42).unwrap().id, 7);
+
+        let area = Area {
+            x1: 0,
+            y1: 0,
+            x2: 12,
+            y2: 12,
+        };
+        let alliances = data_mgr.alliances_in(World::Grass, area);
+        assert_eq!(alliances.len(), 1);
+        assert_eq!(alliances[0].0.name, Some("Knights".to_string()));
+        assert_eq!(alliances[0].1, 1);
+        assert!(data_mgr.alliances_in(World::Ice, area).is_empty());
+
+        // leaving for another alliance
+        data_mgr.add_member(8, 1, None);
+        assert!(data_mgr.alliances[&7].members.is_empty());
+        assert_eq!(data_mgr.alliances[&8].members.len(), 2);
+    }
+
+    #[test]
     fn conflicting_castle_world() {
         let mut data_mgr = DataMgr::new();
         data_mgr.add_castle(Castle {
@@ -220,10 +482,16 @@ mod tests {
             y: Some(20),
             world: Some(World::Fire),
         });
+
+        assert_eq!(data_mgr.castles[&42].world, Some(World::Fire));
+        assert_eq!(data_mgr.castles[&42].x, Some(10));
+        assert_eq!(data_mgr.changes.len(), 1);
+        assert_eq!(data_mgr.changes[0].old, CastleField::World(World::Grass));
+        assert_eq!(data_mgr.changes[0].new, CastleField::World(World::Fire));
+        assert_eq!(data_mgr.changes[0].source, None);
     }
 
     #[test]
-    #[should_panic]
     fn conflicting_castle_position() {
         let mut data_mgr = DataMgr::new();
         data_mgr.add_castle(Castle {
@@ -234,13 +502,29 @@ mod tests {
             y: None,
             world: Some(World::Grass),
         });
-        data_mgr.add_castle(Castle {
-            id: 42,
-            owner_id: None,
-            name: None,
-            x: Some(11),
-            y: Some(20),
-            world: None,
-        });
+        data_mgr.add_castle_from(
+            Castle {
+                id: 42,
+                owner_id: Some(85),
+                name: None,
+                x: Some(11),
+                y: Some(20),
+                world: None,
+            },
+            Some("gaa"),
+        );
+
+        assert_eq!(data_mgr.castles[&42].x, Some(11));
+        assert_eq!(data_mgr.castles[&42].y, Some(20));
+        assert_eq!(data_mgr.castles[&42].name, Some("some name".to_string()));
+        let changes = data_mgr.changes_of(42);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].old, CastleField::Owner(84));
+        assert_eq!(changes[0].new, CastleField::Owner(85));
+        assert_eq!(changes[1].old, CastleField::X(10));
+        assert_eq!(changes[1].new, CastleField::X(11));
+        assert_eq!(changes[1].source, Some("gaa".to_string()));
+        assert!(changes[1].time > 0);
+        assert!(data_mgr.changes_of(43).is_empty());
     }
 }
@@ -1,15 +1,11 @@
+use serde_json::Map;
 use serde_json::value::{Value, from_value};
 
 use error::{ErrorKind, Result, ResultExt};
 use data::Castle;
 use data::World;
-use data::DATAMGR;
-
-macro_rules! try_field{
-    ($data: expr, $field: expr) => {
-        try!(::serde_json::ser::to_string(&$data.get($field)))
-    };
-}
+use packet::{ServerPacket, ClientPacket};
+use super::gdi::parse_gcl;
 
 /// Can parse castles
 pub trait CastleParse {
@@ -43,12 +39,14 @@ impl CastleParse for Castle {
 }
 
 /// The alliance member data
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct FieldAinM {
     /// Internal id
     pub oid: u64,
     /// Username
     pub n: String,
+    /// Rank in the alliance, 0 is the leader
+    pub rank: Option<u64>,
     /// Base castles
     pub ap: Vec<Castle>,
     /// Support castles
@@ -65,6 +63,7 @@ impl FieldAinM {
         struct _FieldAinM__ {
             OID: u64,
             N: String,
+            R: Option<u64>,
             AP: Vec<Value>,
             VP: Vec<Value>,
         }
@@ -81,8 +80,6 @@ impl FieldAinM {
                 let oid = obj.OID; // ain A M [] OID
                 let n = obj.N; // ain A M [] N (username)
 
-                DATAMGR.lock().unwrap().add_owner_name(oid, &n, true);
-
                 let ap = obj.AP
                 //           ^^ ain A M [] AP (base castles)
                     .into_iter()
@@ -98,6 +95,7 @@ impl FieldAinM {
                 Ok(FieldAinM {
                     oid: oid,
                     n: n,
+                    rank: obj.R, // ain A M [] R (rank)
                     ap: ap,
                     vp: vp,
                 })
@@ -106,71 +104,351 @@ impl FieldAinM {
     }
 }
 
-/// Main data
-#[derive(Debug, Clone)]
+/// The own player
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerInfo {
+    /// Internal id
+    pub id: u64,
+    /// Username
+    pub name: Option<String>,
+    /// Internal alliance id
+    pub alliance_id: Option<u64>,
+    /// Level
+    pub level: Option<u64>,
+}
+
+/// Resources stored in one of the own castles
+#[derive(Debug, Clone, PartialEq)]
+pub struct CastleResources {
+    /// Internal castle id
+    pub castle_id: u64,
+    /// World of the castle
+    pub world: World,
+    /// Wood
+    pub wood: f64,
+    /// Stone
+    pub stone: f64,
+    /// Food
+    pub food: f64,
+}
+
+/// The own alliance
+#[derive(Debug, Clone, PartialEq)]
+pub struct AllianceInfo {
+    /// Internal id
+    pub id: u64,
+    /// Alliance name
+    pub name: Option<String>,
+    /// Rank of the own player in the alliance
+    pub rank: Option<u64>,
+}
+
+/// Main data, send by the server after the login
+#[derive(Debug, Clone, PartialEq)]
 pub struct Gbd {
-    /// User data
-    pub gpi: String,
-    /// Alliance member castles
+    /// Own player
+    pub gpi: Option<PlayerInfo>,
+    /// Own castles, without position
+    pub castles: Vec<Castle>,
+    /// Resources of the own castles
+    ///
+    /// Empty when `dcl` doesn't have the expected layout, it is kept in `other` then.
+    pub resources: Vec<CastleResources>,
+    /// Own alliance
+    pub alliance: Option<AllianceInfo>,
+    /// Alliance members and their castles
     pub ain: Vec<FieldAinM>,
+    /// All other sections, for example the chat in `acl`
+    pub other: Map<String, Value>,
 }
 
 impl Gbd {
     /// Parse text returned from the server
     pub fn parse(data: String) -> Result<Self> {
         let data: Value = try!(::serde_json::de::from_str(&data));
-        if !data.is_object() {
-            return Err(ErrorKind::InvalidFormat("gbd not an object".into()).into());
-        }
-        let json_data = data.clone();
-        let mut data = data.as_object().unwrap().clone();
-        data.remove("acl"); // remove chat from output
-        let gpi = try_field!(data, "gpi");
-        let ain = json_data.pointer("/ain/A/M").unwrap(); // ain A M
-        let ain = FieldAinM::parse(ain)?;
-        let gbd = Gbd { gpi: gpi, ain: ain };
-        Ok(gbd)
+        Gbd::parse_val(data)
     }
 
+    /// Parse json data
+    ///
+    /// All sections are optional. Players without alliance have no `ain` section, their `ain`
+    /// is empty.
+    ///
+    /// Only `gpi UID` and `ain` are known from captured packets, `gcl` is read like the section of
+    /// the same name in gdi. `gpi N/AID/L` and `dcl` are read after the names the other packets
+    /// use (`N` is a name in gdi and ain, `AID` an alliance id in ain, `KID` the world
+    /// everywhere), `W/S/F` are assumed to be wood, stone and food. None of these fail the
+    /// packet: `gpi` fields of another type are left out, a `gcl` or `dcl` with another layout
+    /// is logged and kept in `other`.
     pub fn parse_val(data: Value) -> Result<Self> {
-        if !data.is_object() {
-            return Err(ErrorKind::InvalidFormat("gbd not an object".into()).into());
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        #[allow(non_camel_case_types)]
+        /// gpi
+        struct _Gpi {
+            UID: u64,
+            N: Option<Value>,
+            AID: Option<Value>,
+            L: Option<Value>,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        #[allow(non_camel_case_types)]
+        /// dcl, unconfirmed
+        struct _Dcl {
+            C: Vec<_DclC__>,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        #[allow(non_camel_case_types)]
+        /// dcl C []
+        struct _DclC__ {
+            KID: World,
+            AI: Vec<_DclCAI__>,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        #[allow(non_camel_case_types)]
+        /// dcl C [] AI []
+        struct _DclCAI__ {
+            AID: u64,
+            W: Option<f64>,
+            S: Option<f64>,
+            F: Option<f64>,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        #[allow(non_camel_case_types)]
+        /// ain A
+        struct _AinA {
+            AID: Option<u64>,
+            N: Option<String>,
         }
-        let json_data = data.clone();
-        let mut data = data.as_object().unwrap().clone();
-        data.remove("acl"); // remove chat from output
-        let gpi = try_field!(data, "gpi");
-        let ain = json_data.pointer("/ain/A/M").unwrap(); // ain A M
-        let ain = FieldAinM::parse(ain)?;
-        let gbd = Gbd { gpi: gpi, ain: ain };
-        Ok(gbd)
+
+        let mut other = match data {
+            Value::Object(data) => data,
+            _ => return Err(ErrorKind::InvalidFormat("gbd not an object".into()).into()),
+        };
+
+        let gpi = match other.remove("gpi") {
+            Some(Value::Null) | None => None,
+            Some(gpi) => {
+                let gpi: _Gpi = from_value(gpi).chain_err(|| "Cant deserialize gbd gpi")?;
+                Some(PlayerInfo {
+                    id: gpi.UID, // gpi UID
+                    // gpi N (username)
+                    name: gpi.N.as_ref().and_then(Value::as_str).map(str::to_string),
+                    alliance_id: gpi.AID.as_ref().and_then(Value::as_u64), // gpi AID
+                    level: gpi.L.as_ref().and_then(Value::as_u64), // gpi L (level)
+                })
+            }
+        };
+        let own_id = gpi.as_ref().map(|gpi| gpi.id);
+
+        let mut castles = Vec::new();
+        if let Some(gcl) = other.remove("gcl") {
+            match parse_gcl(gcl.clone(), own_id) {
+                Ok(gcl) => castles = gcl,
+                Err(err) => {
+                    warn!(::slog_scope::logger(), "Cant deserialize gbd gcl, kept as is";
+                        "error" => err.to_string());
+                    other.insert("gcl".to_string(), gcl);
+                }
+            }
+        }
+
+        let mut resources = Vec::new();
+        if let Some(dcl_data) = other.remove("dcl") {
+            match from_value::<_Dcl>(dcl_data.clone()) {
+                Ok(dcl) => {
+                    for world in dcl.C {
+                        for castle in world.AI {
+                            resources.push(CastleResources {
+                                castle_id: castle.AID, // dcl C [] AI [] AID (castle id)
+                                world: world.KID, // dcl C [] KID (world)
+                                wood: castle.W.unwrap_or(0.0), // dcl C [] AI [] W (wood)
+                                stone: castle.S.unwrap_or(0.0), // dcl C [] AI [] S (stone)
+                                food: castle.F.unwrap_or(0.0), // dcl C [] AI [] F (food)
+                            });
+                        }
+                    }
+                }
+                Err(err) => {
+                    warn!(::slog_scope::logger(), "Cant deserialize gbd dcl, kept as is";
+                        "error" => err.to_string());
+                    other.insert("dcl".to_string(), dcl_data);
+                }
+            }
+        }
+
+        let mut alliance = None;
+        let mut ain = Vec::new();
+        if let Some(ain_data) = other.remove("ain") {
+            if let Some(members) = ain_data.pointer("/A/M") {
+                ain = FieldAinM::parse(members)?; // ain A M
+            }
+            if let Some(a) = ain_data.get("A") {
+                let a: _AinA = from_value(a.clone()).chain_err(
+                    || "Cant deserialize gbd ain A",
+                )?;
+                let id = a.AID.or_else(|| gpi.as_ref().and_then(|gpi| gpi.alliance_id));
+                if let Some(id) = id {
+                    alliance = Some(AllianceInfo {
+                        id: id, // ain A AID
+                        name: a.N, // ain A N (alliance name)
+                        rank: ain.iter()
+                            .find(|member| Some(member.oid) == own_id)
+                            .and_then(|member| member.rank),
+                    });
+                }
+            }
+        }
+
+        Ok(Gbd {
+            gpi: gpi,
+            castles: castles,
+            resources: resources,
+            alliance: alliance,
+            ain: ain,
+            other: other,
+        })
+    }
+}
+
+/// Handler for gbd packets, stores the own account and the alliance members
+pub fn handle(
+    packet: &ServerPacket,
+    data_mgr: &mut ::data::DataMgr,
+    requests: &mut Vec<ClientPacket>,
+) -> Result<()> {
+    match *packet {
+        ServerPacket::Gbd(ref gbd) => extract(gbd, data_mgr, requests),
+        _ => Err(ErrorKind::InvalidFormat("not a gbd packet".into()).into()),
     }
 }
 
 pub fn extract(
-    obj: Value,
-    con: &mut ::connection::Connection,
+    gbd: &Gbd,
     data_mgr: &mut ::data::DataMgr,
+    _requests: &mut Vec<ClientPacket>,
 ) -> Result<()> {
-    let data = ::slog_scope::scope(&::slog_scope::logger().new(o!("packet"=>"gdb")), || {
-        Gbd::parse_val(obj)
-    }).chain_err(|| "Couldnt read gdb packet")?;
-    for ain in data.ain {
-        for castle in ain.ap {
-            DATAMGR.lock().unwrap().add_castle(castle);
+    if let Some(PlayerInfo { id, name: Some(ref name), .. }) = gbd.gpi {
+        data_mgr.add_owner_name(id, name, gbd.alliance.is_some());
+    }
+    for castle in gbd.castles.iter() {
+        data_mgr.add_castle_from(castle.clone(), Some("gbd"));
+    }
+    if let Some(ref alliance) = gbd.alliance {
+        data_mgr.add_alliance(alliance.id, alliance.name.clone());
+    }
+    for ain in gbd.ain.iter() {
+        data_mgr.add_owner_name(ain.oid, &ain.n, true);
+        if let Some(ref alliance) = gbd.alliance {
+            data_mgr.add_member(alliance.id, ain.oid, ain.rank);
         }
-        for castle in ain.vp {
-            DATAMGR.lock().unwrap().add_castle(castle);
+        for castle in ain.ap.iter().chain(ain.vp.iter()) {
+            data_mgr.add_castle_from(castle.clone(), Some("gbd"));
         }
     }
+    Ok(())
+}
 
-    let users = data_mgr
-        .users
-        .values()
-        .map(|user| user.clone())
-        .collect::<Vec<_>>();
+/// Handler for gbd packets, requests the castles of the own player and every alliance member
+///
+/// Not registered by `data_extractors::register`, as it sends a gdi request per member.
+pub fn request_members(
+    packet: &ServerPacket,
+    _data_mgr: &mut ::data::DataMgr,
+    requests: &mut Vec<ClientPacket>,
+) -> Result<()> {
+    let gbd = match *packet {
+        ServerPacket::Gbd(ref gbd) => gbd,
+        _ => return Err(ErrorKind::InvalidFormat("not a gbd packet".into()).into()),
+    };
+    let mut users = gbd.ain.iter().map(|member| member.oid).collect::<Vec<_>>();
+    if let Some(ref gpi) = gbd.gpi {
+        if !users.contains(&gpi.id) {
+            users.insert(0, gpi.id);
+        }
+    }
     for user in users {
-        con.send_packet(::packet::ClientPacket::Gdi(user.id))?;
+        requests.push(ClientPacket::Gdi(user));
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::de::from_str;
+
+    #[test]
+    fn parse_login_payload() {
+        let gbd = Gbd::parse_val(from_str(r#"{
+            "gpi": {"UID": 1, "N": "tester", "AID": 7, "L": 42},
+            "gcl": {"C": [{"KID": 0, "AI": [{"AI": [1, 10, 20, 100, 1, "Home"]}]}]},
+            "dcl": {"C": [{"KID": 0, "AI": [{"AID": 100, "W": 1200.5, "S": 800, "F": 50}]}]},
+            "ain": {"A": {"AID": 7, "N": "Knights", "M": [
+                {"OID": 1, "N": "tester", "R": 3, "AP": [[0, 100, 10, 20, 1]], "VP": []},
+                {"OID": 2, "N": "leader", "R": 0, "AP": [], "VP": []}
+            ]}},
+            "acl": {"CM": []}
+        }"#).unwrap()).unwrap();
+
+        assert_eq!(
+            gbd.gpi,
+            Some(PlayerInfo {
+                id: 1,
+                name: Some("tester".to_string()),
+                alliance_id: Some(7),
+                level: Some(42),
+            })
+        );
+        assert_eq!(gbd.castles.len(), 1);
+        assert_eq!(gbd.castles[0].owner_id, Some(1));
+        assert_eq!(gbd.castles[0].name, Some("Home".to_string()));
+        assert_eq!(gbd.resources[0].castle_id, 100);
+        assert_eq!(gbd.resources[0].wood, 1200.5);
+        assert_eq!(gbd.resources[0].stone, 800.0);
+        assert_eq!(
+            gbd.alliance,
+            Some(AllianceInfo {
+                id: 7,
+                name: Some("Knights".to_string()),
+                rank: Some(3),
+            })
+        );
+        assert_eq!(gbd.ain[1].rank, Some(0));
+        assert_eq!(gbd.other.keys().collect::<Vec<_>>(), vec!["acl"]);
+    }
+
+    #[test]
+    fn parse_without_alliance() {
+        let gbd = Gbd::parse_val(from_str(r#"{"gpi": {"UID": 1}}"#).unwrap()).unwrap();
+        assert_eq!(gbd.gpi.unwrap().id, 1);
+        assert!(gbd.alliance.is_none());
+        assert!(gbd.ain.is_empty());
+        assert!(gbd.castles.is_empty());
+        assert!(Gbd::parse_val(from_str("[]").unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_unexpected_sections() {
+        let gbd = Gbd::parse_val(from_str(r#"{
+            "gpi": {"UID": 1, "N": "tester", "L": "high"},
+            "gcl": {"C": [{"KID": 0, "AI": ["Home"]}]},
+            "dcl": {"C": [{"KID": 0, "AI": [[100, 1200]]}]}
+        }"#).unwrap()).unwrap();
+        let gpi = gbd.gpi.unwrap();
+        assert_eq!(gpi.name, Some("tester".to_string()));
+        assert_eq!(gpi.level, None);
+        assert!(gbd.resources.is_empty());
+        assert!(gbd.other.contains_key("dcl"));
+        assert!(gbd.castles.is_empty());
+        assert!(gbd.other.contains_key("gcl"));
+    }
+}
@@ -0,0 +1,150 @@
+use serde_json::value::{Value, from_value};
+
+use error::{ErrorKind, Result, ResultExt};
+use packet::{ServerPacket, ClientPacket};
+use data::{Castle, World};
+
+/// Owner of the castles of a gdi packet
+#[derive(Debug, Clone, PartialEq)]
+pub struct GdiOwner {
+    /// Internal id
+    pub id: u64,
+    /// Username
+    pub name: Option<String>,
+    /// Internal alliance id
+    pub alliance_id: Option<u64>,
+}
+
+/// Castles of a specific user
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gdi {
+    /// Owner, when the server send it
+    pub owner: Option<GdiOwner>,
+    /// Named castles, without position
+    pub castles: Vec<Castle>,
+}
+
+impl Gdi {
+    /// Parse json data
+    pub fn parse_val(data: Value) -> Result<Self> {
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        #[allow(non_camel_case_types)]
+        /// self
+        struct _Self {
+            O: Option<_O>,
+            gcl: Option<Value>,
+        }
+
+        #[derive(Deserialize)]
+        #[allow(non_snake_case)]
+        #[allow(non_camel_case_types)]
+        /// O
+        struct _O {
+            OID: u64,
+            N: Option<String>,
+            AID: Option<u64>,
+        }
+
+        let obj: _Self = from_value(data).chain_err(|| "failed to deserialize gdi")?;
+        let owner = obj.O.map(|owner| {
+            GdiOwner {
+                id: owner.OID,
+                name: owner.N,
+                alliance_id: owner.AID,
+            }
+        });
+
+        let castles = match obj.gcl {
+            Some(gcl) => parse_gcl(gcl, owner.as_ref().map(|owner| owner.id))?,
+            None => Vec::new(),
+        };
+
+        Ok(Gdi {
+            owner: owner,
+            castles: castles,
+        })
+    }
+}
+
+/// Parse a castle list, used by gdi and gbd
+///
+/// The castles have no position. Castles without id are skipped.
+pub fn parse_gcl(gcl: Value, owner_id: Option<u64>) -> Result<Vec<Castle>> {
+    #[derive(Deserialize)]
+    #[allow(non_snake_case)]
+    #[allow(non_camel_case_types)]
+    /// gcl
+    struct _Gcl {
+        C: Vec<_GclC__>,
+    }
+
+    #[derive(Deserialize)]
+    #[allow(non_snake_case)]
+    #[allow(non_camel_case_types)]
+    /// gcl C []
+    struct _GclC__ {
+        KID: World,
+        AI: Vec<_GclCAI__>,
+    }
+
+    #[derive(Deserialize)]
+    #[allow(non_snake_case)]
+    #[allow(non_camel_case_types)]
+    /// gcl C [] AI []
+    struct _GclCAI__ {
+        AI: Vec<Value>,
+    }
+
+    let gcl: _Gcl = from_value(gcl).chain_err(|| "failed to deserialize gcl")?;
+    let mut castles = Vec::new();
+    for world in gcl.C {
+        for castle in world.AI {
+            let castle = castle.AI; // gcl C [] AI [] AI (castle)
+            let id = match castle.get(3).and_then(Value::as_u64) {
+                Some(id) => id, // gcl C [] AI [] AI [3] (id)
+                None => continue,
+            };
+            castles.push(Castle {
+                id: id,
+                owner_id: owner_id,
+                name: ::get_name_from_slice(&castle),
+                x: None,
+                y: None,
+                world: Some(world.KID),
+            });
+        }
+    }
+    Ok(castles)
+}
+
+/// Handler for gdi packets
+pub fn handle(
+    packet: &ServerPacket,
+    data_mgr: &mut ::data::DataMgr,
+    requests: &mut Vec<ClientPacket>,
+) -> Result<()> {
+    match *packet {
+        ServerPacket::Gdi(ref gdi) => extract(gdi, data_mgr, requests),
+        _ => Err(ErrorKind::InvalidFormat("not a gdi packet".into()).into()),
+    }
+}
+
+pub fn extract(
+    gdi: &Gdi,
+    data_mgr: &mut ::data::DataMgr,
+    _requests: &mut Vec<ClientPacket>,
+) -> Result<()> {
+    if let Some(ref owner) = gdi.owner {
+        if let Some(ref name) = owner.name {
+            data_mgr.add_owner_name(owner.id, name, false);
+        }
+        if let Some(alliance_id) = owner.alliance_id {
+            data_mgr.add_member(alliance_id, owner.id, None);
+        }
+    }
+    for castle in gdi.castles.iter() {
+        data_mgr.add_castle_from(castle.clone(), Some("gdi"));
+    }
+    Ok(())
+}
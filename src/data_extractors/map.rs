@@ -1,8 +1,12 @@
-use serde_json::value::Value;
+use std::collections::BTreeMap;
+
+use serde_json::value::{Value, from_value};
 use serde_json::de::from_str;
 
-use error::{Result, ResultExt};
-use data::{User, Castle, World};
+use error::{ErrorKind, Result, ResultExt};
+use packet::{ServerPacket, ClientPacket};
+use data::{Alliance, User, Castle, World};
+use map_scan::Area;
 
 trait Flatten<T> {
     fn flatten(self) -> Option<T>;
@@ -18,26 +22,44 @@ impl<T> Flatten<T> for Option<Option<T>> {
 }
 
 /// Map data
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Gaa {
     /// World
     pub kid: World,
+    /// Requested area, when the server echoed it
+    ///
+    /// `Connection::next_response` fills it in from the matched request otherwise.
+    pub area: Option<Area>,
 
     // Parsed
     pub users: Vec<User>,
     pub castles: Vec<Castle>,
     pub castle_names: Vec<Castle>,
+    /// Alliances of the castle owners, without ranks
+    pub alliances: Vec<Alliance>,
 }
 
 impl Gaa {
     /// Parse text returned from the server
     pub fn parse(data: String) -> Result<Self> {
+        let data: Value = from_str(data.trim_matches('%')).chain_err(
+            || "failed to deserialize gaa",
+        )?;
+        Gaa::parse_val(data)
+    }
+
+    /// Parse json data
+    pub fn parse_val(data: Value) -> Result<Self> {
         #[derive(Deserialize)]
         #[allow(non_snake_case)]
         #[allow(non_camel_case_types)]
         /// self
         struct _Self {
             KID: World,
+            AX1: Option<u64>,
+            AY1: Option<u64>,
+            AX2: Option<u64>,
+            AY2: Option<u64>,
             OI: Vec<_OI__>,
             AI: Vec<Value>,
         }
@@ -46,45 +68,69 @@ impl Gaa {
         #[allow(non_snake_case)]
         #[allow(non_camel_case_types)]
         /// OI []
+        ///
+        /// AID and AN haven't been confirmed against a captured gaa packet, they are the names
+        /// ain and gpi use for the alliance id and name. They are ignored when they have an other
+        /// type.
         struct _OI__ {
             OID: u64,
             N: String,
+            AID: Option<Value>,
+            AN: Option<Value>,
             AP: Vec<Value>,
             VP: Vec<Value>,
         }
 
-        let data = data.trim_matches('%');
-        let obj: _Self = from_str(&data).chain_err(
-            || "failed to deserialize gaa",
-        )?;
+        let obj: _Self = from_value(data).chain_err(|| "failed to deserialize gaa")?;
 
         let world: World = obj.KID;
+        let area = match (obj.AX1, obj.AY1, obj.AX2, obj.AY2) {
+            (Some(x1), Some(y1), Some(x2), Some(y2)) => Some(Area {
+                x1: x1,
+                y1: y1,
+                x2: x2,
+                y2: y2,
+            }),
+            _ => None,
+        };
 
         let mut users = Vec::new();
         let mut castles = Vec::new();
         let mut castle_names = Vec::new();
+        let mut alliances = BTreeMap::new();
 
         for user in obj.OI {
+            let aid = user.AID.as_ref().and_then(Value::as_u64); // OI [] AID (alliance id)
             users.push(User {
                 id: user.OID,
                 username: Some(user.N.clone()),
                 own_alliance: false,
+                alliance_id: aid,
             });
+            if let Some(aid) = aid {
+                // OI [] AN (alliance name)
+                let name = user.AN.as_ref().and_then(Value::as_str).map(str::to_string);
+                alliances
+                    .entry(aid)
+                    .or_insert_with(|| Alliance::new(aid, name))
+                    .members
+                    .insert(user.OID, None);
+            }
 
             castles.extend_from_slice(&user.AP
                 .iter()
                 .chain(user.VP.iter())
                 .filter_map(|castle| {
-                    let castle = castle.as_array().unwrap();
-                    if castle.len() < 3 {
+                    let castle = castle.as_array()?;
+                    if castle.len() < 4 {
                         return None;
                     }
                     Some(Castle {
-                        id: castle[1].as_u64().unwrap(),
+                        id: castle[1].as_u64()?,
                         owner_id: Some(user.OID),
                         name: None,
-                        x: Some(castle[2].as_u64().unwrap()),
-                        y: Some(castle[3].as_u64().unwrap()),
+                        x: Some(castle[2].as_u64()?),
+                        y: Some(castle[3].as_u64()?),
                         world: Some(world),
                     })
                 })
@@ -92,16 +138,18 @@ impl Gaa {
         }
 
         for castle in obj.AI {
-            let castle = castle.as_array().unwrap();
+            let castle = match castle.as_array() {
+                Some(castle) => castle,
+                None => continue,
+            };
             if castle.len() < 10 {
                 //warn!(::slog_scope::logger(), "ignoring to short castle {}", Value::Array(castle.to_owned()));
                 continue;
             }
-            let id = castle[3].as_u64();
-            if id.is_none() {
-                continue;
-            }
-            let id = id.unwrap();
+            let (id, x, y) = match (castle[3].as_u64(), castle[1].as_u64(), castle[2].as_u64()) {
+                (Some(id), Some(x), Some(y)) => (id, x, y),
+                _ => continue,
+            };
             let name = ::get_name_from_slice(castle);
 
             trace!(::slog_scope::logger(), "  process castle";
@@ -112,37 +160,73 @@ impl Gaa {
                 id: id,
                 owner_id: None,
                 name: name,
-                x: Some(castle[1].as_u64().unwrap()),
-                y: Some(castle[2].as_u64().unwrap()),
+                x: Some(x),
+                y: Some(y),
                 world: None,
             });
         }
 
         let gaa = Gaa {
             kid: world,
+            area: area,
 
             users: users,
             castles: castles,
             castle_names: castle_names,
+            alliances: alliances.into_iter().map(|(_, alliance)| alliance).collect(),
         };
         Ok(gaa)
     }
 }
 
+/// Handler for gaa packets
+pub fn handle(
+    packet: &ServerPacket,
+    data_mgr: &mut ::data::DataMgr,
+    requests: &mut Vec<ClientPacket>,
+) -> Result<()> {
+    match *packet {
+        ServerPacket::Gaa(ref gaa) => extract(gaa, data_mgr, requests),
+        _ => Err(ErrorKind::InvalidFormat("not a gaa packet".into()).into()),
+    }
+}
+
 pub fn extract(
-    obj: String,
-    _con: &mut ::connection::Connection,
+    gaa: &Gaa,
     data_mgr: &mut ::data::DataMgr,
+    _requests: &mut Vec<ClientPacket>,
 ) -> Result<()> {
-    let gaa = Gaa::parse(obj).chain_err(|| "Couldnt read gaa packet")?;
     for castle in gaa.castles.iter() {
-        data_mgr.add_castle(castle.clone());
+        data_mgr.add_castle_from(castle.clone(), Some("gaa"));
     }
     for castle in gaa.castle_names.iter() {
-        data_mgr.add_castle(castle.clone());
+        data_mgr.add_castle_from(castle.clone(), Some("gaa"));
+    }
+    for alliance in gaa.alliances.iter() {
+        data_mgr.add_alliance(alliance.id, alliance.name.clone());
     }
     for user in gaa.users.iter() {
-        data_mgr.users.insert(user.id, user.clone());
+        if let Some(ref name) = user.username {
+            data_mgr.add_owner_name(user.id, name, false);
+        }
+        if let Some(alliance_id) = user.alliance_id {
+            data_mgr.add_member(alliance_id, user.id, None);
+        }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unexpected_alliance_fields() {
+        let gaa = Gaa::parse(
+            r#"{"KID":0,"OI":[{"OID":3,"N":"neighbour","AID":"9","AN":9,"AP":[],"VP":[]}],"AI":[]}"#
+                .to_string(),
+        ).unwrap();
+        assert_eq!(gaa.users[0].alliance_id, None);
+        assert!(gaa.alliances.is_empty());
+    }
+}
@@ -1,23 +1,15 @@
-use error::{ErrorKind, Result};
-use packet::ServerPacket;
+use dispatcher::Dispatcher;
 
 /// Data reader
 pub mod gbd;
+/// User castles reader
+pub mod gdi;
 /// Map reader
 pub mod map;
 
-pub fn extract(
-    packet: ServerPacket,
-    con: &mut ::connection::Connection,
-    data_mgr: &mut ::data::DataMgr,
-) -> Result<()> {
-    match packet {
-        ServerPacket::Gbd(data) => gbd::extract(data, con, data_mgr),
-        ServerPacket::Gaa(data) => {
-            map::extract(::serde_json::de::from_str(&data).unwrap(), con, data_mgr)
-        }
-        _ => Err(
-            ErrorKind::InvalidFormat("invalid packet type".into()).into(),
-        ),
-    }
+/// Register the handlers of all extractors
+pub fn register(dispatcher: &mut Dispatcher) {
+    dispatcher.register("gbd", gbd::handle);
+    dispatcher.register("gdi", gdi::handle);
+    dispatcher.register("gaa", map::handle);
 }
@@ -0,0 +1,129 @@
+use std::fs::File;
+use std::path::Path;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Mutex};
+
+use serde_json;
+
+use error::{Result, ResultExt};
+
+/// A received frame that couldn't be turned into a packet
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    /// Seconds since the unix epoch
+    pub time: u64,
+    /// Why the frame was rejected
+    pub reason: String,
+    /// The frame as received, without the zero terminator
+    pub data: Vec<u8>,
+}
+
+impl DeadLetter {
+    /// The frame as text, invalid utf8 is replaced
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.data).into_owned()
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    letters: Vec<DeadLetter>,
+    file: Option<BufWriter<File>>,
+}
+
+/// Quarantine for malformed frames
+///
+/// Clones share the same log, so the smartfox client and the connection can both add to it.
+/// The letters are kept in memory and, when created with `create`, written to a file with one
+/// json object per line.
+#[derive(Clone, Default)]
+pub struct DeadLetters {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl DeadLetters {
+    /// Create a log that keeps the letters in memory only
+    pub fn new() -> Self {
+        DeadLetters::default()
+    }
+
+    /// Create or truncate the dead letter file
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::create(path).chain_err(|| "Cant create dead letter file")?;
+        Ok(DeadLetters {
+            inner: Arc::new(Mutex::new(Inner {
+                letters: Vec::new(),
+                file: Some(BufWriter::new(file)),
+            })),
+        })
+    }
+
+    /// Quarantine a frame, it is flushed immediately so a crash doesn't lose it
+    pub fn push(&self, reason: &str, data: &[u8]) -> Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or(0);
+        let letter = DeadLetter {
+            time: time,
+            reason: reason.to_string(),
+            data: data.to_vec(),
+        };
+        let mut inner = self.inner.lock().expect("Cant lock dead letters");
+        if let Some(ref mut file) = inner.file {
+            serde_json::to_writer(&mut *file, &letter)?;
+            file.write_all(b"\n").chain_err(|| "Cant write dead letter")?;
+            file.flush().chain_err(|| "Cant write dead letter")?;
+        }
+        inner.letters.push(letter);
+        Ok(())
+    }
+
+    /// All quarantined frames
+    pub fn letters(&self) -> Vec<DeadLetter> {
+        self.inner.lock().expect("Cant lock dead letters").letters.clone()
+    }
+
+    /// Amount of quarantined frames
+    pub fn len(&self) -> usize {
+        self.inner.lock().expect("Cant lock dead letters").letters.len()
+    }
+
+    /// Whether no frame was quarantined
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn quarantine_frames() {
+        let path = env::temp_dir().join(format!(
+            "gge-dead-letters-{}.json",
+            ::std::process::id()
+        ));
+        let dead_letters = DeadLetters::create(&path).unwrap();
+        let shared = dead_letters.clone();
+        assert!(dead_letters.is_empty());
+
+        dead_letters.push("invalid utf8", &[b'%', 0xff, b'%']).unwrap();
+        shared.push("invalid gbd", b"%xt%gbd%1%0%[]%").unwrap();
+        assert_eq!(dead_letters.len(), 2);
+        assert_eq!(shared.letters()[0].text(), "%\u{fffd}%");
+
+        let file = fs::read_to_string(&path).unwrap();
+        let letters = file.lines()
+            .map(|line| serde_json::from_str::<DeadLetter>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(letters, dead_letters.letters());
+        assert_eq!(letters[0].data, vec![b'%', 0xff, b'%']);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,322 @@
+use std::fmt;
+use std::collections::BTreeMap;
+
+use data::{Castle, DataMgr, User, World};
+
+/// Owner change of a castle between two snapshots
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OwnerChange {
+    /// Internal castle id
+    pub castle_id: u64,
+    /// Internal owner id in the old snapshot
+    pub old_owner: u64,
+    /// Internal owner id in the new snapshot
+    pub new_owner: u64,
+}
+
+/// Name change of a castle between two snapshots
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Rename {
+    /// Internal castle id
+    pub castle_id: u64,
+    /// Name in the old snapshot
+    pub old_name: String,
+    /// Name in the new snapshot
+    pub new_name: String,
+}
+
+/// Position of a castle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Position {
+    /// X position
+    pub x: u64,
+    /// Y position
+    pub y: u64,
+    /// World
+    pub world: Option<World>,
+}
+
+impl Position {
+    fn of(castle: &Castle) -> Option<Position> {
+        Some(Position {
+            x: castle.x?,
+            y: castle.y?,
+            world: castle.world,
+        })
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.world {
+            Some(world) => write!(f, "{},{} ({:?})", self.x, self.y, world),
+            None => write!(f, "{},{}", self.x, self.y),
+        }
+    }
+}
+
+/// Position change of a castle between two snapshots
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Relocation {
+    /// Internal castle id
+    pub castle_id: u64,
+    /// Position in the old snapshot
+    pub old: Position,
+    /// Position in the new snapshot
+    pub new: Position,
+}
+
+/// Differences between two snapshots, see `diff`
+///
+/// Every list is sorted by id.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize)]
+pub struct Diff {
+    /// Castles only in the new snapshot
+    pub added_castles: Vec<Castle>,
+    /// Castles only in the old snapshot
+    pub removed_castles: Vec<Castle>,
+    /// Castles that were conquered
+    pub owner_changes: Vec<OwnerChange>,
+    /// Castles that got an other name
+    pub renames: Vec<Rename>,
+    /// Castles that moved
+    pub relocations: Vec<Relocation>,
+    /// Users only in the new snapshot
+    pub added_users: Vec<User>,
+    /// Users only in the old snapshot
+    pub removed_users: Vec<User>,
+    /// Usernames of both snapshots, for printing
+    #[serde(skip)]
+    names: BTreeMap<u64, String>,
+}
+
+impl Diff {
+    /// Are the snapshots the same?
+    pub fn is_empty(&self) -> bool {
+        self.added_castles.is_empty() && self.removed_castles.is_empty() &&
+            self.owner_changes.is_empty() && self.renames.is_empty() &&
+            self.relocations.is_empty() && self.added_users.is_empty() &&
+            self.removed_users.is_empty()
+    }
+
+    fn user(&self, id: Option<u64>) -> String {
+        match id {
+            Some(id) => {
+                match self.names.get(&id) {
+                    Some(name) => format!("{} ({})", name, id),
+                    None => id.to_string(),
+                }
+            }
+            None => "unknown".to_string(),
+        }
+    }
+}
+
+/// Compare two snapshots
+///
+/// Fields that are unknown to one of the snapshots are not reported as changed.
+pub fn diff(old: &DataMgr, new: &DataMgr) -> Diff {
+    let mut diff = Diff::default();
+    for user in old.users.values().chain(new.users.values()) {
+        if let Some(ref name) = user.username {
+            diff.names.insert(user.id, name.clone());
+        }
+    }
+
+    let old_castles = old.castles.iter().collect::<BTreeMap<_, _>>();
+    let new_castles = new.castles.iter().collect::<BTreeMap<_, _>>();
+    for (id, castle) in &old_castles {
+        if !new_castles.contains_key(id) {
+            diff.removed_castles.push((*castle).clone());
+        }
+    }
+    for (id, castle) in &new_castles {
+        let old_castle = match old_castles.get(id) {
+            Some(old_castle) => old_castle,
+            None => {
+                diff.added_castles.push((*castle).clone());
+                continue;
+            }
+        };
+        if let (Some(old_owner), Some(new_owner)) = (old_castle.owner_id, castle.owner_id) {
+            if old_owner != new_owner {
+                diff.owner_changes.push(OwnerChange {
+                    castle_id: castle.id,
+                    old_owner: old_owner,
+                    new_owner: new_owner,
+                });
+            }
+        }
+        if let (&Some(ref old_name), &Some(ref new_name)) = (&old_castle.name, &castle.name) {
+            if old_name != new_name {
+                diff.renames.push(Rename {
+                    castle_id: castle.id,
+                    old_name: old_name.clone(),
+                    new_name: new_name.clone(),
+                });
+            }
+        }
+        if let (Some(old_pos), Some(new_pos)) = (Position::of(old_castle), Position::of(castle)) {
+            if old_pos != new_pos {
+                diff.relocations.push(Relocation {
+                    castle_id: castle.id,
+                    old: old_pos,
+                    new: new_pos,
+                });
+            }
+        }
+    }
+
+    let old_users = old.users.iter().collect::<BTreeMap<_, _>>();
+    let new_users = new.users.iter().collect::<BTreeMap<_, _>>();
+    for (id, user) in &old_users {
+        if !new_users.contains_key(id) {
+            diff.removed_users.push((*user).clone());
+        }
+    }
+    for (id, user) in &new_users {
+        if !old_users.contains_key(id) {
+            diff.added_users.push((*user).clone());
+        }
+    }
+
+    diff
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No changes");
+        }
+        for castle in &self.added_castles {
+            write!(f, "+ castle {}", castle.id)?;
+            if let Some(ref name) = castle.name {
+                write!(f, " \"{}\"", name)?;
+            }
+            writeln!(f, " of {}", self.user(castle.owner_id))?;
+        }
+        for castle in &self.removed_castles {
+            write!(f, "- castle {}", castle.id)?;
+            if let Some(ref name) = castle.name {
+                write!(f, " \"{}\"", name)?;
+            }
+            writeln!(f, " of {}", self.user(castle.owner_id))?;
+        }
+        for change in &self.owner_changes {
+            writeln!(
+                f,
+                "~ castle {} conquered: {} -> {}",
+                change.castle_id,
+                self.user(Some(change.old_owner)),
+                self.user(Some(change.new_owner))
+            )?;
+        }
+        for rename in &self.renames {
+            writeln!(
+                f,
+                "~ castle {} renamed: \"{}\" -> \"{}\"",
+                rename.castle_id,
+                rename.old_name,
+                rename.new_name
+            )?;
+        }
+        for relocation in &self.relocations {
+            writeln!(
+                f,
+                "~ castle {} moved: {} -> {}",
+                relocation.castle_id,
+                relocation.old,
+                relocation.new
+            )?;
+        }
+        for user in &self.added_users {
+            writeln!(f, "+ user {}", self.user(Some(user.id)))?;
+        }
+        for user in &self.removed_users {
+            writeln!(f, "- user {}", self.user(Some(user.id)))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn castle(id: u64, owner_id: u64, name: &str, x: u64) -> Castle {
+        Castle {
+            id: id,
+            owner_id: Some(owner_id),
+            name: Some(name.to_string()),
+            x: Some(x),
+            y: Some(5),
+            world: Some(World::Grass),
+        }
+    }
+
+    #[test]
+    fn diff_snapshots() {
+        let mut old = DataMgr::new();
+        old.add_owner_name(1, "alice", false);
+        old.add_owner_name(2, "bob", false);
+        old.add_castle(castle(10, 1, "Keep", 1));
+        old.add_castle(castle(11, 1, "Tower", 2));
+        old.add_castle(castle(12, 2, "Hut", 3));
+
+        let mut new = DataMgr::new();
+        new.add_owner_name(1, "alice", false);
+        new.add_owner_name(3, "carol", false);
+        new.add_castle(castle(10, 3, "Keep", 1));
+        new.add_castle(castle(11, 1, "Spire", 4));
+        new.add_castle(castle(13, 3, "Farm", 6));
+
+        let diff = diff(&old, &new);
+        assert_eq!(diff.added_castles, vec![castle(13, 3, "Farm", 6)]);
+        assert_eq!(diff.removed_castles, vec![castle(12, 2, "Hut", 3)]);
+        assert_eq!(
+            diff.owner_changes,
+            vec![OwnerChange { castle_id: 10, old_owner: 1, new_owner: 3 }]
+        );
+        assert_eq!(diff.renames.len(), 1);
+        assert_eq!(diff.renames[0].new_name, "Spire");
+        assert_eq!(diff.relocations.len(), 1);
+        assert_eq!(diff.relocations[0].new.x, 4);
+        assert_eq!(diff.added_users.len(), 1);
+        assert_eq!(diff.added_users[0].id, 3);
+        assert_eq!(diff.removed_users[0].id, 2);
+
+        let text = diff.to_string();
+        assert!(text.contains("~ castle 10 conquered: alice (1) -> carol (3)"));
+        assert!(text.contains("~ castle 11 moved: 2,5 (Grass) -> 4,5 (Grass)"));
+        assert!(text.contains("- user bob (2)"));
+
+        let json = ::serde_json::to_value(&diff).unwrap();
+        assert_eq!(json["owner_changes"][0]["new_owner"], 3);
+        assert!(json.get("names").is_none());
+
+        assert!(super::diff(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn snapshot_json_roundtrip() {
+        let mut data = DataMgr::new();
+        data.add_owner_name(1, "alice", true);
+        data.add_member(4, 1, Some(0));
+        data.add_castle(castle(10, 1, "Keep", 1));
+        data.add_castle(castle(10, 1, "Keep", 2));
+        data.add_castle(Castle {
+            world: Some(World::Fire),
+            ..castle(10, 1, "Keep", 2)
+        });
+        let json = ::to_json(&data).unwrap();
+        let loaded: DataMgr = ::serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.castles, data.castles);
+        assert_eq!(loaded.users, data.users);
+        assert_eq!(loaded.alliances, data.alliances);
+        assert_eq!(loaded.changes, data.changes);
+
+        // only snapshots name their worlds, server data uses the kingdom id
+        assert_eq!(::serde_json::from_str::<World>("2").unwrap(), World::Ice);
+        assert!(::serde_json::from_str::<World>("\"Ice\"").is_err());
+    }
+}
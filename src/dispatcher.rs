@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use error::Result;
+use packet::{ServerPacket, ClientPacket};
+use data::DataMgr;
+
+/// Processes the packets of one or more commands
+///
+/// Requests pushed to `requests` are send by the caller of the dispatcher.
+pub trait Handler: Send {
+    /// Process a decoded packet
+    fn handle(
+        &mut self,
+        packet: &ServerPacket,
+        data_mgr: &mut DataMgr,
+        requests: &mut Vec<ClientPacket>,
+    ) -> Result<()>;
+}
+
+impl<F> Handler for F
+where
+    F: FnMut(&ServerPacket, &mut DataMgr, &mut Vec<ClientPacket>) -> Result<()> + Send,
+{
+    fn handle(
+        &mut self,
+        packet: &ServerPacket,
+        data_mgr: &mut DataMgr,
+        requests: &mut Vec<ClientPacket>,
+    ) -> Result<()> {
+        self(packet, data_mgr, requests)
+    }
+}
+
+/// Routes packets to the handlers registered for their command
+#[derive(Default)]
+pub struct Dispatcher {
+    handlers: HashMap<String, Vec<Box<Handler>>>,
+}
+
+impl Dispatcher {
+    /// Create a dispatcher without handlers
+    pub fn new() -> Self {
+        Dispatcher::default()
+    }
+
+    /// Create a dispatcher with the handlers of all data extractors
+    pub fn with_extractors() -> Self {
+        let mut dispatcher = Dispatcher::new();
+        ::data_extractors::register(&mut dispatcher);
+        dispatcher
+    }
+
+    /// Register a handler for a command, for example "gaa"
+    ///
+    /// Handlers of the same command are called in the order they were registered.
+    pub fn register<H: Handler + 'static>(&mut self, command: &str, handler: H) {
+        self.handlers
+            .entry(command.to_string())
+            .or_insert_with(Vec::new)
+            .push(Box::new(handler));
+    }
+
+    /// Whether a handler is registered for the command
+    pub fn handles(&self, command: &str) -> bool {
+        self.handlers.contains_key(command)
+    }
+
+    /// Pass the packet to the handlers of its command
+    ///
+    /// Returns false when no handler is registered for the command.
+    /// Stops at the first failing handler.
+    /// Error responses of the server are returned as `ErrorKind::ServerError`
+    /// without calling the handlers.
+    pub fn dispatch(
+        &mut self,
+        packet: &ServerPacket,
+        data_mgr: &mut DataMgr,
+        requests: &mut Vec<ClientPacket>,
+    ) -> Result<bool> {
+        if let Some(err) = packet.error() {
+            return Err(err);
+        }
+        let handlers = match self.handlers.get_mut(packet.command()) {
+            Some(handlers) => handlers,
+            None => return Ok(false),
+        };
+        for handler in handlers.iter_mut() {
+            handler.handle(packet, data_mgr, requests)?;
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::{Error, ErrorKind};
+    use data::World;
+
+    fn count_castles(
+        packet: &ServerPacket,
+        data_mgr: &mut DataMgr,
+        requests: &mut Vec<ClientPacket>,
+    ) -> Result<()> {
+        if let ServerPacket::Gdi(ref gdi) = *packet {
+            requests.push(ClientPacket::Gdi(data_mgr.castles.len() as u64));
+            assert_eq!(gdi.castles.len(), 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn dispatch_by_command() {
+        let mut dispatcher = Dispatcher::with_extractors();
+        dispatcher.register("gdi", count_castles);
+        assert!(dispatcher.handles("gaa"));
+        assert!(!dispatcher.handles("kpi"));
+
+        let gdi = ServerPacket::new(
+            r#"%xt%gdi%1%0%{"O":{"OID":7,"N":"tester"},"gcl":{"C":[{"KID":0,"AI":[{"AI":[1,2,3,42,4,5,6,7,8,9,10,"Castle"]}]}]}}%"#
+                .to_string(),
+        ).unwrap();
+        let mut data_mgr = DataMgr::new();
+        let mut requests = Vec::new();
+        assert!(dispatcher.dispatch(&gdi, &mut data_mgr, &mut requests).unwrap());
+
+        assert_eq!(data_mgr.castles[&42].name, Some("Castle".to_string()));
+        assert_eq!(data_mgr.castles[&42].world, Some(World::Grass));
+        assert_eq!(data_mgr.users[&7].username, Some("tester".to_string()));
+        // the extractor runs first
+        assert_eq!(requests, vec![ClientPacket::Gdi(1)]);
+
+        let kpi = ServerPacket::new("%xt%kpi%1%0%{}%".to_string()).unwrap();
+        assert!(!dispatcher.dispatch(&kpi, &mut data_mgr, &mut requests).unwrap());
+
+        let failed = ServerPacket::new("%xt%gdi%1%114%{}%".to_string()).unwrap();
+        match dispatcher.dispatch(&failed, &mut data_mgr, &mut requests) {
+            Err(Error(ErrorKind::ServerError(_, 114), _)) => {}
+            result => panic!("{:?}", result),
+        }
+        assert_eq!(requests.len(), 1);
+    }
+
+    #[test]
+    fn gbd_requests_gdi() {
+        let mut dispatcher = Dispatcher::with_extractors();
+        dispatcher.register("gbd", ::data_extractors::gbd::request_members);
+        let gbd = ServerPacket::new(
+            r#"%xt%gbd%1%0%{"ain":{"A":{"M":[{"OID":3,"N":"member","AP":[[0,10,1,2,1]],"VP":[]}]}}}%"#
+                .to_string(),
+        ).unwrap();
+        let mut data_mgr = DataMgr::new();
+        let mut requests = Vec::new();
+        dispatcher.dispatch(&gbd, &mut data_mgr, &mut requests).unwrap();
+
+        assert_eq!(data_mgr.castles[&10].owner_id, Some(3));
+        assert_eq!(requests, vec![ClientPacket::Gdi(3)]);
+    }
+}
@@ -0,0 +1,145 @@
+use std::io::prelude::*;
+use std::io::{self, BufReader};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+
+use slog::Logger;
+
+use scenario::{Request, Responder};
+use smartfox::{SysMessage, SMARTFOX_VERSION};
+
+/// Server mimicking the game server, the answers to game requests come from a `Responder`
+///
+/// Every client is served by its own thread. All clients share the responder.
+pub struct DummyServer {
+    addr: SocketAddr,
+    connections: Arc<AtomicUsize>,
+    thread: JoinHandle<()>,
+}
+
+impl DummyServer {
+    /// Start a server on an ephemeral port of localhost
+    pub fn start<R: Responder + 'static>(responder: R, logger: Logger) -> io::Result<Self> {
+        DummyServer::bind("127.0.0.1:0", responder, logger)
+    }
+
+    /// Start a server on the given address
+    pub fn bind<A, R>(addr: A, responder: R, logger: Logger) -> io::Result<Self>
+    where
+        A: ToSocketAddrs,
+        R: Responder + 'static,
+    {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let connections = Arc::new(AtomicUsize::new(0));
+        let responder = Arc::new(Mutex::new(responder));
+        info!(logger, "dummy server listening"; "address" => addr.to_string());
+
+        let counter = connections.clone();
+        let thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        warn!(logger, "accept failed"; "error" => err.to_string());
+                        continue;
+                    }
+                };
+                counter.fetch_add(1, Ordering::SeqCst);
+                let responder = responder.clone();
+                let logger = logger.clone();
+                thread::spawn(move || if let Err(err) = serve(stream, &*responder, &logger) {
+                    debug!(logger, "connection failed"; "error" => err.to_string());
+                });
+            }
+        });
+
+        Ok(DummyServer {
+            addr: addr,
+            connections: connections,
+            thread: thread,
+        })
+    }
+
+    /// The address the server listens on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Amount of accepted connections
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    /// Block until the server stops, which only happens when accepting connections panics
+    pub fn wait(self) {
+        let _ = self.thread.join();
+    }
+}
+
+/// Answer the packets of a single client until it disconnects
+fn serve<R: Responder>(
+    stream: TcpStream,
+    responder: &Mutex<R>,
+    logger: &Logger,
+) -> io::Result<()> {
+    let logger = logger.new(o!("peer" => stream.peer_addr()?.to_string()));
+    debug!(logger, "connected");
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+
+    loop {
+        let mut frame = Vec::new();
+        if reader.read_until(0, &mut frame)? == 0 {
+            debug!(logger, "disconnected");
+            return Ok(());
+        }
+        if frame.last() == Some(&0) {
+            frame.pop();
+        }
+        let frame = String::from_utf8_lossy(&frame).into_owned();
+        trace!(logger, "recv"; "data" => frame.clone());
+
+        if let Some(msg) = SysMessage::parse(&frame) {
+            let answer = match &*msg.action {
+                "verChk" if msg.content.contains(&format!("v='{}'", SMARTFOX_VERSION)) => {
+                    "<msg t='sys'><body action='apiOK' r='0'></body></msg>"
+                }
+                "verChk" => "<msg t='sys'><body action='apiKO' r='0'></body></msg>",
+                "login" => {
+                    concat!(
+                        "<msg t='sys'><body action='logOK' r='0'>",
+                        "<login n='' id='1' mod='0'/></body></msg>"
+                    )
+                }
+                _ => continue,
+            };
+            send(&mut writer, answer)?;
+            continue;
+        }
+
+        let request = match Request::parse(&frame) {
+            Some(request) => request,
+            None => {
+                debug!(logger, "ignoring unknown packet"; "data" => frame);
+                continue;
+            }
+        };
+        let response = responder.lock().unwrap().respond(&request);
+        thread::sleep(response.delay);
+        for packet in &response.packets {
+            send(&mut writer, packet)?;
+        }
+        if response.disconnect {
+            debug!(logger, "closing connection"; "command" => request.command);
+            return writer.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+fn send(writer: &mut TcpStream, packet: &str) -> io::Result<()> {
+    writer.write_all(packet.as_bytes())?;
+    writer.write_all(&[0])
+}
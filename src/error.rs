@@ -2,6 +2,7 @@ use std::io;
 use std::borrow::Cow;
 
 use serde_json::error::Error as SerdeJsonError;
+use rusqlite::Error as SqliteError;
 
 error_chain!{
     types{
@@ -11,6 +12,7 @@ error_chain!{
     foreign_links{
         IoError(io::Error);
         SerdeJsonError(SerdeJsonError);
+        SqliteError(SqliteError);
     }
 
     errors{
@@ -18,5 +20,65 @@ error_chain!{
             description("invalid format")
             display("The json returned from the server has a invalid format: {}", descr)
         }
+        VersionMismatch(version: u32){
+            description("smartfox version rejected")
+            display("The server doesn't support SmartFoxServer client version {}", version)
+        }
+        LoginRejected(reason: String){
+            description("smartfox login rejected")
+            display("The server rejected the SmartFoxServer login: {}", reason)
+        }
+        HandshakeFailed(descr: String){
+            description("smartfox handshake failed")
+            display("SmartFoxServer handshake failed: {}", descr)
+        }
+        LoginFailed(code: i64){
+            description("login failed")
+            display("Login failed: {}", ::packet::LoginStatus::from_code(*code))
+        }
+        Timeout{
+            description("timeout")
+            display("The server didn't answer in time")
+        }
+        Disconnected{
+            description("disconnected")
+            display("The server closed the connection")
+        }
+        ConnectionLost(descr: Cow<'static, str>){
+            description("connection lost")
+            display("Connection to the server lost: {}", descr)
+        }
+        InvalidRequest(descr: Cow<'static, str>){
+            description("invalid request")
+            display("Invalid request: {}", descr)
+        }
+        UnknownServer(name: String){
+            description("unknown server")
+            display("Unknown server '{}'", name)
+        }
+        InvalidArea(area: String){
+            description("invalid map area")
+            display("Invalid map area '{}', expected 'all' or 'x1,y1,x2,y2'", area)
+        }
+        InvalidCapture(descr: Cow<'static, str>){
+            description("invalid capture")
+            display("Invalid capture file: {}", descr)
+        }
+        InvalidPacket(descr: Cow<'static, str>){
+            description("invalid packet")
+            display("Received invalid packet: {}", descr)
+        }
+        ServerError(command: String, status: i64){
+            description("server returned an error")
+            display("The server answered {} with status {}", command, status)
+        }
+        UnknownRun(id: i64){
+            description("unknown import run")
+            display("There is no import run with id {}", id)
+        }
+        CaptureEnded{
+            description("capture ended")
+            display("All packets of the capture have been replayed")
+        }
     }
 }
@@ -0,0 +1,184 @@
+use rusqlite::Row;
+
+use error::Result;
+use storage::{ImportRun, SqliteStorage};
+
+/// Owner of a castle as seen by an import run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ownership {
+    /// Id of the import run
+    pub run_id: i64,
+    /// When the run finished, seconds since the unix epoch
+    pub time: u64,
+    /// Internal owner id, None when the owner wasn't known to the run
+    pub owner_id: Option<u64>,
+}
+
+impl Ownership {
+    fn from_row(row: &Row) -> Self {
+        Ownership {
+            run_id: row.get(0),
+            time: row.get::<_, i64>(1) as u64,
+            owner_id: row.get::<_, Option<i64>>(2).map(|id| id as u64),
+        }
+    }
+}
+
+/// Castle that got an other owner between two import runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnerChange {
+    /// Internal castle id
+    pub castle_id: u64,
+    /// Owner in the older run
+    pub old_owner: u64,
+    /// Owner in the newer run
+    pub new_owner: u64,
+}
+
+/// Queries over the castle history of the import runs
+///
+/// Castle and user ids are only unique on one server, so every query is limited to the runs of
+/// `server`, the name stored in `ImportRun::server`.
+impl SqliteStorage {
+    /// The owner of a castle in every run that saw the castle, oldest first
+    pub fn castle_timeline(&self, server: &str, castle_id: u64) -> Result<Vec<Ownership>> {
+        let mut stmt = self.connection().prepare(
+            "SELECT import_runs.id, import_runs.finished, castles.owner_id
+             FROM castles JOIN import_runs ON import_runs.id = castles.run_id
+             WHERE import_runs.server = ? AND castles.id = ?
+             ORDER BY import_runs.finished, import_runs.id",
+        )?;
+        let rows = stmt.query_map(&[&server, &(castle_id as i64)], Ownership::from_row)?;
+        let mut timeline = Vec::new();
+        for ownership in rows {
+            timeline.push(ownership?);
+        }
+        Ok(timeline)
+    }
+
+    /// The owner of a castle at `time` (seconds since the unix epoch)
+    ///
+    /// This is the owner according to the last run that finished at or before `time` and saw
+    /// the castle.
+    pub fn owner_at(&self, server: &str, castle_id: u64, time: u64) -> Result<Option<Ownership>> {
+        Ok(
+            self.castle_timeline(server, castle_id)?
+                .into_iter()
+                .take_while(|ownership| ownership.time <= time)
+                .last(),
+        )
+    }
+
+    /// Castles with a different owner in run `to` than in run `from`
+    ///
+    /// Castles of which one of the runs doesn't know the owner are left out.
+    pub fn owner_changes(&self, from: i64, to: i64) -> Result<Vec<OwnerChange>> {
+        let mut stmt = self.connection().prepare(
+            "SELECT old.id, old.owner_id, new.owner_id
+             FROM castles AS old JOIN castles AS new ON new.id = old.id
+             WHERE old.run_id = ? AND new.run_id = ? AND old.owner_id != new.owner_id
+             ORDER BY old.id",
+        )?;
+        let rows = stmt.query_map(&[&from, &to], |row| {
+            OwnerChange {
+                castle_id: row.get::<_, i64>(0) as u64,
+                old_owner: row.get::<_, i64>(1) as u64,
+                new_owner: row.get::<_, i64>(2) as u64,
+            }
+        })?;
+        let mut changes = Vec::new();
+        for change in rows {
+            changes.push(change?);
+        }
+        Ok(changes)
+    }
+
+    /// The first run that saw a user, either as user or as owner of a castle
+    ///
+    /// Runs are ordered by the time they finished, like in `castle_timeline`.
+    pub fn first_seen(&self, server: &str, user_id: u64) -> Result<Option<(i64, ImportRun)>> {
+        let user_id = user_id as i64;
+        let mut stmt = self.connection().prepare(
+            "SELECT id, server, started, finished FROM import_runs
+             WHERE server = ? AND id IN (
+                 SELECT run_id FROM users WHERE id = ?
+                 UNION SELECT run_id FROM castles WHERE owner_id = ?
+             )
+             ORDER BY finished, id
+             LIMIT 1",
+        )?;
+        let mut rows = stmt.query_map(&[&server, &user_id, &user_id], |row| {
+            (
+                row.get::<_, i64>(0),
+                ImportRun {
+                    server: row.get(1),
+                    started: row.get::<_, i64>(2) as u64,
+                    finished: row.get::<_, i64>(3) as u64,
+                },
+            )
+        })?;
+        match rows.next() {
+            Some(run) => Ok(Some(run?)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data::{Castle, DataMgr};
+
+    fn import(storage: &mut SqliteStorage, server: &str, time: u64, owners: &[(u64, u64)]) -> i64 {
+        let mut data = DataMgr::new();
+        for &(castle_id, owner_id) in owners {
+            data.add_castle(Castle {
+                id: castle_id,
+                owner_id: Some(owner_id),
+                name: None,
+                x: None,
+                y: None,
+                world: None,
+            });
+        }
+        let run = ImportRun {
+            server: server.to_string(),
+            started: time - 60,
+            finished: time,
+        };
+        data.flush(storage, &run).unwrap().unwrap()
+    }
+
+    #[test]
+    fn castle_history() {
+        let mut storage = SqliteStorage::in_memory().unwrap();
+        let first = import(&mut storage, "dutch", 1000, &[(1, 10), (2, 20)]);
+        let second = import(&mut storage, "dutch", 2000, &[(1, 10), (2, 30)]);
+        let third = import(&mut storage, "dutch", 3000, &[(1, 30), (2, 30), (3, 40)]);
+        // the same ids on an other server are other castles and users
+        import(&mut storage, "german", 1500, &[(1, 50), (2, 50)]);
+
+        assert_eq!(storage.owner_at("dutch", 2, 999).unwrap(), None);
+        assert_eq!(storage.owner_at("dutch", 2, 1500).unwrap().unwrap().owner_id, Some(20));
+        assert_eq!(storage.owner_at("dutch", 2, 2000).unwrap().unwrap().run_id, second);
+        assert_eq!(storage.owner_at("dutch", 3, 2500).unwrap(), None);
+        assert_eq!(storage.owner_at("german", 2, 2000).unwrap().unwrap().owner_id, Some(50));
+        assert_eq!(storage.castle_timeline("dutch", 1).unwrap().len(), 3);
+
+        assert_eq!(
+            storage.owner_changes(first, second).unwrap(),
+            vec![OwnerChange { castle_id: 2, old_owner: 20, new_owner: 30 }]
+        );
+        assert_eq!(storage.owner_changes(first, third).unwrap().len(), 2);
+
+        assert_eq!(storage.first_seen("dutch", 30).unwrap().unwrap().0, second);
+        assert_eq!(storage.first_seen("dutch", 40).unwrap().unwrap().1.finished, 3000);
+        assert_eq!(storage.first_seen("dutch", 50).unwrap(), None);
+        assert_eq!(storage.first_seen("german", 50).unwrap().unwrap().1.server, "german");
+
+        // a backfilled snapshot of an earlier day
+        let backfilled = import(&mut storage, "dutch", 500, &[(2, 30)]);
+        assert_eq!(storage.first_seen("dutch", 30).unwrap().unwrap().0, backfilled);
+        assert_eq!(storage.owner_at("dutch", 2, 600).unwrap().unwrap().run_id, backfilled);
+    }
+}
@@ -7,20 +7,27 @@ extern crate error_chain;
 #[macro_use]
 extern crate lazy_static;
 extern crate regex;
+extern crate rand;
 
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
 extern crate serde_json;
 
 extern crate smartfox as smartfox_c;
+extern crate rusqlite;
+
+#[macro_use]
+extern crate futures;
+extern crate tokio_core;
+extern crate tokio_codec;
+extern crate bytes;
 
 pub use serde_json::ser::to_string as to_json;
-use serde_json::from_str;
-use serde_json::value::{Value, from_value};
+use serde_json::value::Value;
 
-use data::DATAMGR;
-use error::ResultExt;
+use data::DataMgr;
 
 /// Error
 pub mod error;
@@ -30,65 +37,61 @@ pub mod packet;
 pub mod data;
 /// Data extractors
 pub mod data_extractors;
+/// Snapshot storage
+pub mod storage;
+/// Castle history over the stored snapshots
+pub mod history;
+/// Differences between two snapshots
+pub mod diff;
+/// Packet handler registry
+pub mod dispatcher;
 /// Smartfoxserver client
 pub mod smartfox;
+/// Packet capture and replay
+pub mod capture;
+/// Quarantine for malformed packets
+pub mod dead_letter;
 /// Goodgame empire connection
 pub mod connection;
+/// Request/response correlation
+pub mod correlation;
+/// Keepalive and reconnecting session
+pub mod session;
+/// Request rate limiting
+pub mod pacer;
+/// Asynchronous goodgame empire connection
+pub mod async_connection;
+/// Server registry
+pub mod servers;
+/// Map scanner
+pub mod map_scan;
+/// Dummy server scenarios
+pub mod scenario;
+/// Synthetic world generator
+pub mod world_gen;
+/// In-process dummy game server
+pub mod dummy_server;
 
 mod byte_stream_splitter;
 
 /// Read castles
-pub fn read_castles(data: data_extractors::gbd::Gbd) {
+pub fn read_castles(data: data_extractors::gbd::Gbd, data_mgr: &mut DataMgr) {
     for ain in data.ain {
         for castle in ain.ap {
-            DATAMGR.lock().unwrap().add_castle(castle);
+            data_mgr.add_castle(castle);
         }
         for castle in ain.vp {
-            DATAMGR.lock().unwrap().add_castle(castle);
+            data_mgr.add_castle(castle);
         }
     }
 }
 
-pub fn read_names(data: String) -> error::Result<()> {
-    let data = data.trim_right_matches('%');
-    let data: Value = from_str(data).chain_err(
-        || "Cant parse json in gge::read_names",
-    )?;
-    let data = data.as_object().ok_or(error::ErrorKind::InvalidFormat(
-        "Root not a object in gge::read_names"
-            .into(),
-    ))?;
-    let gcl = data.get("gcl").unwrap().as_object().unwrap(); // gcl
-    let c = gcl.get("C").unwrap().as_array().unwrap(); // gcl C
-    for world in c.iter() {
-        let world = world.as_object().unwrap();
-        let world_name: data::World = from_value(world.get("KID").unwrap().clone()).unwrap(); // gcl C [] KID
-        let world = world.get("AI").unwrap().as_array().unwrap(); // gcl C [] AI
-        for castle in world {
-            let castle = castle
-                .as_object()
-                .unwrap()
-                .get("AI")
-                .unwrap()
-                .as_array()
-                .unwrap(); // gcl C [] AI [] AI (castle)
-            let castle_id = castle[3].as_u64().unwrap(); // gcl C [] AI [] AI [3] (id)
-            println!("castle: {}", Value::Array(castle.to_owned()));
-            let castle_name = get_name_from_slice(castle).expect("Invalid gcl C [] AI [] AI"); // gcl C [] AI [] AI [10] (name)
-
-            let castle = data::Castle {
-                id: castle_id,
-                owner_id: None,
-                name: Some(castle_name.to_string()),
-                x: None,
-                y: None,
-                world: Some(world_name),
-            };
-            trace!(slog_scope::logger(), "processed castle";  "castle" => format!("{:?}", castle));
-            DATAMGR.lock().unwrap().add_castle(castle);
-        }
+/// Read castle names
+pub fn read_names(gdi: &data_extractors::gdi::Gdi, data_mgr: &mut DataMgr) {
+    for castle in gdi.castles.iter() {
+        trace!(slog_scope::logger(), "processed castle";  "castle" => format!("{:?}", castle));
+        data_mgr.add_castle(castle.clone());
     }
-    Ok(())
 }
 
 fn get_name_from_slice(slice: &[Value]) -> Option<String> {
@@ -10,13 +10,29 @@ extern crate gge;
 use std::env;
 use std::io;
 use std::io::Write;
+use std::str::FromStr;
 use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use gge::error::{self, ResultExt};
-use gge::to_json;
-use gge::packet::{ServerPacket, ClientPacket};
-use gge::connection::{Connection, LOCAL_SERVER, DUTCH_SERVER};
-use gge::data::DATAMGR;
+use gge::packet::ServerPacket;
+use gge::dispatcher::Dispatcher;
+use gge::data_extractors::{gbd, gdi, map};
+use gge::session::Session;
+use gge::pacer::Pacer;
+use gge::capture::{Recorder, Replay};
+use gge::dead_letter::DeadLetters;
+use gge::storage::{ImportRun, JsonFile, SqliteStorage, Storage};
+use gge::diff::diff;
+use gge::servers::ServerRegistry;
+use gge::data::{DataMgr, World};
+use gge::map_scan::{Area, MapScan, MAP_SIZE, TILE_SIZE};
+
+/// Maximum amount of times the missing map tiles are requested
+const MAP_SCAN_ROUNDS: u32 = 3;
+
+/// Amount of map tiles requested before reading their answers
+const MAP_SCAN_BATCH: usize = 50;
 
 fn main() {
     let log_file = std::fs::OpenOptions::new()
@@ -59,69 +75,206 @@ fn main() {
     }
 }
 
+/// Command line options
+struct Options {
+    /// Name of the server to import from
+    server: String,
+    /// Json file with extra servers
+    servers_file: Option<String>,
+    /// Capture file to record all packets to
+    record: Option<String>,
+    /// Capture file to replay instead of connecting to the server
+    replay: Option<String>,
+    /// File malformed packets are quarantined to
+    dead_letters: Option<String>,
+    /// Sqlite database the import is stored in instead of a json file
+    database: Option<String>,
+    /// Request the castles of every alliance member after the login
+    request_members: bool,
+    /// Map area to scan, either `all` or `x1,y1,x2,y2`. Nothing is scanned when it is None.
+    map_area: Option<String>,
+}
+
+fn parse_args() -> error::Result<Options> {
+    let mut options = Options {
+        server: env_or_default("GGE_SERVER", "dutch"),
+        servers_file: env::var("GGE_SERVERS").ok(),
+        record: env::var("GGE_RECORD").ok(),
+        replay: env::var("GGE_REPLAY").ok(),
+        dead_letters: env::var("GGE_DEAD_LETTERS").ok(),
+        database: env::var("GGE_DATABASE").ok(),
+        request_members: env::var("GGE_REQUEST_MEMBERS").is_ok(),
+        map_area: env::var("GGE_MAP_AREA").ok(),
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--server" => {
+                options.server = args.next().ok_or("Missing server name after --server")?;
+            }
+            "--servers" => {
+                options.servers_file = Some(args.next().ok_or("Missing file after --servers")?);
+            }
+            "--record" => {
+                options.record = Some(args.next().ok_or("Missing file after --record")?);
+            }
+            "--replay" => {
+                options.replay = Some(args.next().ok_or("Missing file after --replay")?);
+            }
+            "--dead-letters" => {
+                options.dead_letters =
+                    Some(args.next().ok_or("Missing file after --dead-letters")?);
+            }
+            "--request-members" => options.request_members = true,
+            "--map-area" => {
+                options.map_area = Some(args.next().ok_or("Missing area after --map-area")?);
+            }
+            "--db" => {
+                options.database = Some(args.next().ok_or("Missing file after --db")?);
+            }
+            _ => return Err(format!("Unknown argument {}", arg).into()),
+        }
+    }
+    if options.record.is_some() && options.replay.is_some() {
+        return Err("--record and --replay can't be used together".into());
+    }
+    Ok(options)
+}
+
 fn run() -> gge::error::Result<()> {
+    if env::args().nth(1).map_or(false, |arg| arg == "diff") {
+        return run_diff();
+    }
+
     let logger = slog_scope::logger();
-    io::stderr().write(b"Please login\n").chain_err(
-        || "Cant write to stderr",
-    )?;
-    let un: String = env_or_ask("GGE_USERNAME", "Username: ");
-    let pw: String = env_or_ask("GGE_PASSWORD", "Password: ");
+    let options = parse_args()?;
+    let started = unix_time();
+    let registry = match options.servers_file {
+        Some(ref file) => ServerRegistry::load(file)?,
+        None => ServerRegistry::default(),
+    };
+    let server = registry.get(&options.server).chain_err(|| {
+        format!("Known servers: {}", registry.names().join(", "))
+    })?;
+    info!(logger, "selected server";
+        "name" => server.name.clone(),
+        "address" => server.address.clone());
+
+    let mut session = match options.replay {
+        Some(ref file) => {
+            info!(logger, "replaying capture"; "file" => file.clone());
+            Session::replay(Replay::open(file)?, server.config.clone(), logger.clone())?
+        }
+        None => {
+            let recorder = match options.record {
+                Some(ref file) => {
+                    info!(logger, "recording capture"; "file" => file.clone());
+                    Some(Recorder::create(file)?)
+                }
+                None => None,
+            };
 
-    let mut con = Connection::new(*DUTCH_SERVER, &un, &pw, logger.clone())?;
+            io::stderr().write(b"Please login\n").chain_err(
+                || "Cant write to stderr",
+            )?;
+            let un: String = env_or_ask("GGE_USERNAME", "Username: ");
+            let pw: String = env_or_ask("GGE_PASSWORD", "Password: ");
 
-    for pkt in con.read_packets(logger.clone())? {
+            Session::recording(
+                server.socket_addr()?,
+                server.config.clone(),
+                &un,
+                &pw,
+                recorder,
+                logger.clone(),
+            )?
+        }
+    };
+
+    if let Some(ref file) = options.dead_letters {
+        info!(logger, "quarantining malformed packets"; "file" => file.clone());
+        session.set_dead_letters(DeadLetters::create(file)?);
+    }
+
+    let deadline = env_number("GGE_DEADLINE", 60, "a number of seconds")?;
+    session.set_deadline(Duration::new(deadline, 0));
+
+    let rate = env_number("GGE_RATE", 5.0, "a number of requests per second")?;
+    if !(rate > 0.0) {
+        return Err("GGE_RATE must be positive".into());
+    }
+    let burst = env_number("GGE_BURST", 10, "a number of requests")?;
+    let jitter = env_number("GGE_JITTER_MS", 200, "a number of milliseconds")?;
+    if options.replay.is_none() {
+        session.set_pacer(Some(
+            Pacer::new(rate, burst).with_jitter(Duration::from_millis(jitter)),
+        ));
+    }
+
+    let mut dispatcher = Dispatcher::new();
+    dispatcher.register("gbd", gbd::handle);
+    if options.request_members {
+        dispatcher.register("gbd", gbd::request_members);
+    }
+    dispatcher.register("gdi", gdi::handle);
+    dispatcher.register("gaa", map::handle);
+    let mut data_mgr = DataMgr::new();
+
+    while let Some(pkt) = session.next_response()? {
         slog_scope::scope(&logger.new(o!("process"=>"pre map")), || {
-            process_packet(&mut con, pkt)
+            process_packet(&mut session, &mut dispatcher, &mut data_mgr, None, pkt)
         })?;
     }
 
     debug!(logger.clone(), "");
 
-    con.send_packet(ClientPacket::Gaa(r#"{"AY1":676,"AY2":688,"KID":0,"AX1":546,"AX2":558}"#.to_string()))?;
-    con.send_packet(ClientPacket::Gaa(r#"{"AY1":676,"AY2":688,"KID":0,"AX1":559,"AX2":571}"#.to_string()))?;
-    con.send_packet(ClientPacket::Gaa(r#"{"AY1":676,"AY2":688,"KID":0,"AX1":572,"AX2":584}"#.to_string()))?;
-    //con.send_packet(ClientPacket::Gaa(r#"{"AY1":689,"AY2":701,"KID":0,"AX1":546,"AX2":558}"#.to_string()))?;
-    //con.send_packet(ClientPacket::Gaa(r#"{"AY1":689,"AY2":701,"KID":0,"AX1":559,"AX2":571}"#.to_string()))?;
-    //con.send_packet(ClientPacket::Gaa(r#"{"AY1":689,"AY2":701,"KID":0,"AX1":572,"AX2":584}"#.to_string()))?;
-    //con.send_packet(ClientPacket::Gaa(r#"{"AY1":702,"AY2":714,"KID":0,"AX1":546,"AX2":558}"#.to_string()))?;
-    con.send_packet(ClientPacket::Gaa(r#"{"AY1":702,"AY2":714,"KID":0,"AX1":559,"AX2":571}"#.to_string()))?;
-    con.send_packet(ClientPacket::Gaa(r#"{"AY1":702,"AY2":714,"KID":0,"AX1":572,"AX2":584}"#.to_string()))?;
-    con.send_packet(ClientPacket::Gaa(r#"{"AY1":806,"AY2":818,"KID":0,"AX1":338,"AX2":350}"#.to_string()))?;
-    con.send_packet(ClientPacket::Gaa(r#"{"AY1":663,"AY2":675,"KID":0,"AX1":546,"AX2":558}"#.to_string()))?;
-    con.send_packet(ClientPacket::Gaa(r#"{"AY1":663,"AY2":675,"KID":0,"AX1":559,"AX2":571}"#.to_string()))?;
-    con.send_packet(ClientPacket::Gaa(r#"{"AY1":663,"AY2":675,"KID":0,"AX1":572,"AX2":584}"#.to_string()))?;
-    //con.send_packet(ClientPacket::Gaa(r#"{"AY1":650,"AY2":662,"KID":0,"AX1":559,"AX2":571}"#.to_string()))?;
-    //con.send_packet(ClientPacket::Gaa(r#"{"AY1":650,"AY2":662,"KID":0,"AX1":572,"AX2":584}"#.to_string()))?;
-    //con.send_packet(ClientPacket::Gaa(r#"{"AY1":650,"AY2":662,"KID":0,"AX1":585,"AX2":597}"#.to_string()))?;
-    //con.send_packet(ClientPacket::Gaa(r#"{"AY1":663,"AY2":675,"KID":0,"AX1":585,"AX2":597}"#.to_string()))?;
-    //con.send_packet(ClientPacket::Gaa(r#"{"AY1":676,"AY2":688,"KID":0,"AX1":585,"AX2":597}"#.to_string()))?;
-    //con.send_packet(ClientPacket::Gaa(r#"{"AY1":637,"AY2":649,"KID":0,"AX1":559,"AX2":571}"#.to_string()))?;
-    //con.send_packet(ClientPacket::Gaa(r#"{"AY1":637,"AY2":649,"KID":0,"AX1":572,"AX2":584}"#.to_string()))?;
-    //con.send_packet(ClientPacket::Gaa(r#"{"AY1":637,"AY2":649,"KID":0,"AX1":585,"AX2":597}"#.to_string()))?;
-    con.send_packet(ClientPacket::Gaa(r#"{"AX2":350,"KID":0,"AY1":806,"AY2":818,"AX1":338}"#.to_string()))?;
+    if let Some(ref area) = options.map_area {
+        let map_size = env_number("GGE_MAP_SIZE", MAP_SIZE, "a number of fields")?;
+        let area = Area::parse_with_size(area, map_size)?;
+        let mut scan = MapScan::new(World::Grass, area, TILE_SIZE);
+        info!(logger, "scanning map";
+            "area" => format!("{:?}", area),
+            "tiles" => scan.tiles().len());
 
-    debug!(logger.clone(), "");
+        while !scan.is_complete() && (scan.unsent() > 0 || scan.rounds() < MAP_SCAN_ROUNDS) {
+            let send = scan.send_pending(&mut session, MAP_SCAN_BATCH)?;
+            debug!(logger, "requested map tiles";
+                "round" => scan.rounds(),
+                "tiles" => send,
+                "unsent" => scan.unsent());
 
-    for pkt in con.read_packets(logger.clone())? {
-        slog_scope::scope(&logger.new(o!("process"=>"post map")), || {
-            process_packet(&mut con, pkt)
-        })?;
+            while let Some(pkt) = session.next_response()? {
+                slog_scope::scope(&logger.new(o!("process"=>"post map")), || {
+                    process_packet(
+                        &mut session,
+                        &mut dispatcher,
+                        &mut data_mgr,
+                        Some(&mut scan),
+                        pkt,
+                    )
+                })?;
+            }
+        }
+
+        if !scan.is_complete() {
+            warn!(logger, "map scan incomplete"; "missing" => scan.pending().len());
+        }
+    } else {
+        info!(logger, "no map area given, skipping the map scan");
+    }
+    info!(logger, "import done"; "reconnects" => session.reconnects());
+    if !session.dead_letters().is_empty() {
+        warn!(logger, "malformed packets were skipped"; "count" => session.dead_letters().len());
     }
 
     debug!(logger.clone(), "");
 
-    for castle in DATAMGR
-        .lock()
-        .expect("Cant lock DATAMGR")
-        .castles
-        .values()
-        .take(40)
-    {
+    for castle in data_mgr.castles.values().take(40) {
         info!(logger.clone(), "     read castle"; "castle" => format!("{:?}", castle));
     }
     let mut max_x = 0;
     let mut max_y = 0;
-    for castle in DATAMGR.lock().unwrap().castles.values() {
+    for castle in data_mgr.castles.values() {
         if let Some(x) = castle.x {
             if x > max_x { max_x = x; }
         }
@@ -131,55 +284,74 @@ fn run() -> gge::error::Result<()> {
     }
     info!(logger.clone(), " maximum coordinates x={} y={}", max_x, max_y);
 
-    let file_name = env_or_default("GGE_FILENAME", "data2.json");
+    let mut storage: Box<Storage> = match options.database {
+        Some(ref file) => Box::new(SqliteStorage::open(file)?),
+        None => Box::new(JsonFile::new(env_or_default("GGE_FILENAME", "data2.json"))),
+    };
+    let run = ImportRun {
+        server: server.name.clone(),
+        started: started,
+        finished: unix_time(),
+    };
+    if let Some(run_id) = data_mgr.flush(&mut *storage, &run)? {
+        info!(logger, "stored import run"; "id" => run_id);
+    }
+    Ok(())
+}
 
-    let mut f = std::fs::OpenOptions::new()
-        .read(false)
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(file_name)
-        .chain_err(|| "Cant open data file")?;
-
-    write!(
-        f,
-        "{}",
-        to_json(&*DATAMGR.lock().expect("Cant lock DATAMGR"))
-            .chain_err(|| "Cant serialize data")?
-    ).chain_err(|| "Cant write data to file")
+/// `gge diff <old> <new> [--json]`: print the changes between two data files
+fn run_diff() -> error::Result<()> {
+    let mut files = Vec::new();
+    let mut json = false;
+    for arg in env::args().skip(2) {
+        match &*arg {
+            "--json" => json = true,
+            _ if arg.starts_with("--") => return Err(format!("Unknown argument {}", arg).into()),
+            _ => files.push(arg),
+        }
+    }
+    if files.len() != 2 {
+        return Err("Usage: gge diff <old data file> <new data file> [--json]".into());
+    }
+    let old = JsonFile::new(&files[0]).load().chain_err(|| files[0].clone())?;
+    let new = JsonFile::new(&files[1]).load().chain_err(|| files[1].clone())?;
+    let diff = diff(&old, &new);
+    if json {
+        println!("{}", gge::to_json(&diff).chain_err(|| "Cant serialize diff")?);
+    } else {
+        print!("{}", diff);
+    }
+    Ok(())
 }
 
-fn process_packet(con: &mut Connection, pkt: ServerPacket) -> error::Result<()> {
+fn process_packet(
+    session: &mut Session,
+    dispatcher: &mut Dispatcher,
+    data_mgr: &mut DataMgr,
+    scan: Option<&mut MapScan>,
+    pkt: ServerPacket,
+) -> error::Result<()> {
     let logger = slog_scope::logger();
-    match pkt {
-        ServerPacket::Gbd(ref data) => {
-            /*let data = &*data;
-            let data = slog_scope::scope(&logger.new(o!("packet"=>"gdb")), || {
-                gge::data_extractors::gbd::Gbd::parse_val(data.to_owned())
-            }).chain_err(|| "Couldnt read gdb packet")?;
-            gge::read_castles(data.clone());
-
-            let data_mgr = DATAMGR.lock().unwrap();
-            let users = data_mgr
-                .users
-                .values()
-                .map(|user| user.clone())
-                .collect::<Vec<_>>();
-            for user in users {
-                con.send_packet(ClientPacket::Gdi(user.id))?;
-            }*/
-        }
-        ServerPacket::Gdi(data) => {
-            slog_scope::scope(&logger.new(o!("packet"=>"gdi")), || gge::read_names(data))?;
-        }
-        ServerPacket::Gaa(data) => {
-            trace!(logger, "gaa packet"; "data" => data.clone());
-            let gaa = slog_scope::scope(&logger.new(o!("packet"=>"gaa")), || {
-                gge::data_extractors::map::extract(data, con, &mut *DATAMGR.lock().unwrap())
-            }).chain_err(|| "Couldnt read gaa packet")?;
+    if let Some(err) = pkt.error() {
+        // missing map tiles are requested again by the map scan
+        warn!(logger, "server returned an error"; "error" => err.to_string());
+        return Ok(());
+    }
+
+    let mut requests = Vec::new();
+    slog_scope::scope(&logger.new(o!("packet" => pkt.command().to_string())), || {
+        dispatcher.dispatch(&pkt, data_mgr, &mut requests)
+    }).chain_err(|| format!("Couldnt read {} packet", pkt.command()))?;
+    for request in requests {
+        session.send_packet(request)?;
+    }
+
+    if let (Some(scan), &ServerPacket::Gaa(ref gaa)) = (scan, &pkt) {
+        if !scan.mark_answered(gaa) {
+            debug!(logger, "gaa packet not part of map scan";
+                "area" => format!("{:?}", gaa.area));
         }
-        _ => {}
-    };
+    }
     Ok(())
 }
 
@@ -208,3 +380,27 @@ fn env_or_default(env_name: &str, default: &str) -> String {
         })
         .unwrap_or_else(|_| default.trim().to_string())
 }
+
+/// Parse a number from an environment variable, `default` when it isn't set
+fn env_number<T>(env_name: &str, default: T, expected: &str) -> error::Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + 'static,
+{
+    match env::var(env_name) {
+        Ok(data) => {
+            data.trim().parse::<T>().chain_err(
+                || format!("{} is not {}", env_name, expected),
+            )
+        }
+        Err(_) => Ok(default),
+    }
+}
+
+/// Seconds since the unix epoch
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,325 @@
+use std::str::FromStr;
+use std::collections::{BTreeSet, VecDeque};
+
+use error::{Error, ErrorKind, Result};
+use data::World;
+use connection::PacketSender;
+use packet::{ClientPacket, GaaRequest};
+use data_extractors::map::Gaa;
+
+/// Maximum width and height of a single map request accepted by the server
+pub const TILE_SIZE: u64 = 13;
+
+/// Default width and height of a kingdom map
+///
+/// Not confirmed by the server: 99 tiles of 13 fields, which covers every coordinate the
+/// importer has requested so far (the old hardcoded requests went up to 818). Use
+/// `Area::parse_with_size` for bigger maps.
+///
+/// It is not confirmed either that gaa answers echo the requested area as `AX1/AY1/AX2/AY2`.
+/// Answers without them are matched with the oldest outstanding gaa request by
+/// `Connection::next_response`, so a scan completes either way.
+pub const MAP_SIZE: u64 = 1287;
+
+/// Rectangular part of the map. Both corners are inclusive.
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Area {
+    /// Left
+    pub x1: u64,
+    /// Top
+    pub y1: u64,
+    /// Right
+    pub x2: u64,
+    /// Bottom
+    pub y2: u64,
+}
+
+impl Area {
+    /// The whole map of a kingdom, assuming it is `MAP_SIZE` fields wide
+    pub fn whole_world() -> Self {
+        Area::whole_map(MAP_SIZE)
+    }
+
+    /// The whole map of a kingdom which is `map_size` fields wide and high
+    pub fn whole_map(map_size: u64) -> Self {
+        Area {
+            x1: 0,
+            y1: 0,
+            x2: map_size - 1,
+            y2: map_size - 1,
+        }
+    }
+
+    /// Parse either `all` or `x1,y1,x2,y2` for a map of `map_size` by `map_size` fields
+    pub fn parse_with_size(s: &str, map_size: u64) -> Result<Self> {
+        let s = s.trim();
+        if s == "all" {
+            return Ok(Area::whole_map(map_size));
+        }
+        let coords = s.split(',')
+            .map(|coord| coord.trim().parse::<u64>())
+            .collect::<::std::result::Result<Vec<_>, _>>()
+            .map_err(|_| ErrorKind::InvalidArea(s.to_string()))?;
+        if coords.len() != 4 || coords[0] > coords[2] || coords[1] > coords[3] ||
+            coords[2] >= map_size || coords[3] >= map_size
+        {
+            return Err(ErrorKind::InvalidArea(s.to_string()).into());
+        }
+        Ok(Area {
+            x1: coords[0],
+            y1: coords[1],
+            x2: coords[2],
+            y2: coords[3],
+        })
+    }
+
+    /// Split the area in tiles of at most `tile_size` by `tile_size` fields
+    pub fn tiles(&self, tile_size: u64) -> Vec<Area> {
+        assert!(tile_size > 0, "tile size must not be zero");
+        let mut tiles = Vec::new();
+        let mut y = self.y1;
+        while y <= self.y2 {
+            let mut x = self.x1;
+            while x <= self.x2 {
+                tiles.push(Area {
+                    x1: x,
+                    y1: y,
+                    x2: ::std::cmp::min(x + tile_size - 1, self.x2),
+                    y2: ::std::cmp::min(y + tile_size - 1, self.y2),
+                });
+                x += tile_size;
+            }
+            y += tile_size;
+        }
+        tiles
+    }
+}
+
+impl FromStr for Area {
+    type Err = Error;
+
+    /// Parse either `all` or `x1,y1,x2,y2`, see `Area::parse_with_size`
+    fn from_str(s: &str) -> Result<Self> {
+        Area::parse_with_size(s, MAP_SIZE)
+    }
+}
+
+/// Plans and tracks the map requests needed to import an area of a world
+#[derive(Debug, Clone)]
+pub struct MapScan {
+    world: World,
+    tiles: Vec<Area>,
+    answered: BTreeSet<Area>,
+    /// Pending tiles not yet requested in the current round
+    unsent: VecDeque<Area>,
+    rounds: u32,
+}
+
+impl MapScan {
+    /// Create a scan of `area` in `world` using tiles of `tile_size` fields
+    pub fn new(world: World, area: Area, tile_size: u64) -> Self {
+        MapScan {
+            world: world,
+            tiles: area.tiles(tile_size),
+            answered: BTreeSet::new(),
+            unsent: VecDeque::new(),
+            rounds: 0,
+        }
+    }
+
+    /// Create a scan of the whole map of `world`
+    pub fn whole_world(world: World, tile_size: u64) -> Self {
+        MapScan::new(world, Area::whole_world(), tile_size)
+    }
+
+    /// The scanned world
+    pub fn world(&self) -> World {
+        self.world
+    }
+
+    /// All tiles of this scan
+    pub fn tiles(&self) -> &[Area] {
+        &self.tiles
+    }
+
+    /// Tiles for which no answer has been received yet
+    pub fn pending(&self) -> Vec<Area> {
+        self.tiles
+            .iter()
+            .filter(|tile| !self.answered.contains(tile))
+            .cloned()
+            .collect()
+    }
+
+    /// Have all tiles been answered?
+    pub fn is_complete(&self) -> bool {
+        self.answered.len() == self.tiles.len()
+    }
+
+    /// Number of times all pending tiles have been requested, including the current round
+    pub fn rounds(&self) -> u32 {
+        self.rounds
+    }
+
+    /// Pending tiles that haven't been requested in the current round
+    pub fn unsent(&self) -> usize {
+        self.unsent.len()
+    }
+
+    /// The requests for all pending tiles
+    ///
+    /// Fails when the tiles are bigger than the server accepts.
+    pub fn pending_requests(&self) -> Result<Vec<ClientPacket>> {
+        self.pending()
+            .into_iter()
+            .map(|tile| GaaRequest::new(self.world, tile).map(ClientPacket::Gaa))
+            .collect()
+    }
+
+    /// Send requests for at most `max` pending tiles
+    ///
+    /// The answers should be read before sending the next batch, so they don't pile up in the
+    /// receive buffer. A new round starts when every pending tile of the current round has been
+    /// requested.
+    ///
+    /// Returns the amount of send requests
+    pub fn send_pending<S: PacketSender>(&mut self, con: &mut S, max: usize) -> Result<usize> {
+        if self.unsent.is_empty() {
+            self.unsent = self.pending().into_iter().collect();
+            if self.unsent.is_empty() {
+                return Ok(0);
+            }
+            self.rounds += 1;
+        }
+        let mut count = 0;
+        while count < max {
+            let tile = match self.unsent.pop_front() {
+                Some(tile) => tile,
+                None => break,
+            };
+            if self.answered.contains(&tile) {
+                continue;
+            }
+            con.send_packet(ClientPacket::Gaa(GaaRequest::new(self.world, tile)?))?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Mark the tile the map data belongs to as answered
+    ///
+    /// Returns false when the data doesn't belong to a tile of this scan, or has no area because
+    /// it didn't come through `Connection::next_response`
+    pub fn mark_answered(&mut self, gaa: &Gaa) -> bool {
+        if gaa.kid != self.world {
+            return false;
+        }
+        let area = match gaa.area {
+            Some(area) => area,
+            None => return false,
+        };
+        if self.tiles.contains(&area) {
+            self.answered.insert(area);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_in_tiles() {
+        let area = Area {
+            x1: 10,
+            y1: 20,
+            x2: 35,
+            y2: 32,
+        };
+        assert_eq!(
+            area.tiles(13),
+            vec![
+                Area { x1: 10, y1: 20, x2: 22, y2: 32 },
+                Area { x1: 23, y1: 20, x2: 35, y2: 32 },
+            ]
+        );
+        assert_eq!(
+            area.tiles(10).last(),
+            Some(&Area { x1: 30, y1: 30, x2: 35, y2: 32 })
+        );
+        assert_eq!(Area::whole_world().tiles(TILE_SIZE).len(), 99 * 99);
+    }
+
+    #[test]
+    fn parse_area() {
+        assert_eq!("all".parse::<Area>().unwrap(), Area::whole_world());
+        assert_eq!(
+            "546, 676,584,714".parse::<Area>().unwrap(),
+            Area { x1: 546, y1: 676, x2: 584, y2: 714 }
+        );
+        assert!("584,676,546,714".parse::<Area>().is_err());
+        assert!("1,2,3".parse::<Area>().is_err());
+        assert!("0,0,0,5000".parse::<Area>().is_err());
+        assert_eq!(
+            Area::parse_with_size("0,0,0,5000", 6000).unwrap(),
+            Area { x1: 0, y1: 0, x2: 0, y2: 5000 }
+        );
+        assert_eq!(Area::parse_with_size("all", 26).unwrap().tiles(TILE_SIZE).len(), 4);
+    }
+
+    #[test]
+    fn track_answers() {
+        let mut scan = MapScan::new(
+            World::Grass,
+            Area { x1: 0, y1: 0, x2: 25, y2: 12 },
+            TILE_SIZE,
+        );
+        assert_eq!(scan.pending().len(), 2);
+
+        let gaa = Gaa::parse(
+            r#"{"KID":0,"AX1":13,"AY1":0,"AX2":25,"AY2":12,"OI":[],"AI":[]}"#.to_string(),
+        ).unwrap();
+        assert!(scan.mark_answered(&gaa));
+        assert_eq!(scan.pending(), vec![Area { x1: 0, y1: 0, x2: 12, y2: 12 }]);
+        assert!(!scan.is_complete());
+
+        let other_world = Gaa::parse(
+            r#"{"KID":1,"AX1":0,"AY1":0,"AX2":12,"AY2":12,"OI":[],"AI":[]}"#.to_string(),
+        ).unwrap();
+        assert!(!scan.mark_answered(&other_world));
+    }
+
+    #[test]
+    fn send_in_batches() {
+        struct Sent(Vec<ClientPacket>);
+
+        impl PacketSender for Sent {
+            fn send_packet(&mut self, packet: ClientPacket) -> Result<()> {
+                self.0.push(packet);
+                Ok(())
+            }
+        }
+
+        let mut scan = MapScan::new(
+            World::Grass,
+            Area { x1: 0, y1: 0, x2: 38, y2: 12 },
+            TILE_SIZE,
+        );
+        let mut sent = Sent(Vec::new());
+        assert_eq!(scan.send_pending(&mut sent, 2).unwrap(), 2);
+        assert_eq!((scan.rounds(), scan.unsent()), (1, 1));
+        assert_eq!(scan.send_pending(&mut sent, 2).unwrap(), 1);
+        assert_eq!((scan.rounds(), scan.unsent()), (1, 0));
+
+        let gaa = Gaa::parse(
+            r#"{"KID":0,"AX1":13,"AY1":0,"AX2":25,"AY2":12,"OI":[],"AI":[]}"#.to_string(),
+        ).unwrap();
+        scan.mark_answered(&gaa);
+        assert_eq!(scan.send_pending(&mut sent, 5).unwrap(), 2);
+        assert_eq!(scan.rounds(), 2);
+        assert_eq!(sent.0.len(), 5);
+    }
+}
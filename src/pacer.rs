@@ -0,0 +1,112 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::{self, Rng};
+
+/// Token bucket limiting the rate at which requests are send
+#[derive(Debug, Clone)]
+pub struct Pacer {
+    /// Requests per second
+    rate: f64,
+    /// Maximum amount of requests send back-to-back
+    burst: f64,
+    /// Maximum random extra wait before each request
+    jitter: Duration,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Pacer {
+    /// Allow `rate` requests per second with bursts of at most `burst` requests
+    pub fn new(rate: f64, burst: u32) -> Self {
+        assert!(rate > 0.0, "request rate must be positive");
+        let burst = f64::from(::std::cmp::max(burst, 1));
+        Pacer {
+            rate: rate,
+            burst: burst,
+            jitter: Duration::new(0, 0),
+            tokens: burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Wait a random time of at most `jitter` before each request
+    pub fn with_jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = duration_to_secs(now.duration_since(self.last_refill));
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+    }
+
+    /// Time to wait before the next request may be send
+    pub fn delay(&mut self) -> Duration {
+        self.refill(Instant::now());
+        if self.tokens >= 1.0 {
+            Duration::new(0, 0)
+        } else {
+            secs_to_duration((1.0 - self.tokens) / self.rate)
+        }
+    }
+
+    /// Take a token, or return how long to wait for one
+    fn try_acquire(&mut self) -> Option<Duration> {
+        let delay = self.delay();
+        if delay == Duration::new(0, 0) {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(delay)
+        }
+    }
+
+    /// Block until a request may be send
+    pub fn wait(&mut self) {
+        while let Some(delay) = self.try_acquire() {
+            thread::sleep(delay);
+        }
+        if self.jitter > Duration::new(0, 0) {
+            let jitter = duration_to_secs(self.jitter) * rand::thread_rng().gen::<f64>();
+            thread::sleep(secs_to_duration(jitter));
+        }
+    }
+}
+
+fn duration_to_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1_000_000_000.0
+}
+
+fn secs_to_duration(secs: f64) -> Duration {
+    Duration::new(secs.trunc() as u64, (secs.fract() * 1_000_000_000.0) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_then_rate() {
+        let mut pacer = Pacer::new(10.0, 3);
+        for _ in 0..3 {
+            assert_eq!(pacer.try_acquire(), None);
+        }
+        let delay = pacer.try_acquire().unwrap();
+        assert!(delay > Duration::from_millis(50) && delay <= Duration::from_millis(100));
+
+        let start = Instant::now();
+        pacer.wait();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn refill_is_capped() {
+        let mut pacer = Pacer::new(100.0, 2);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(pacer.try_acquire(), None);
+        assert_eq!(pacer.try_acquire(), None);
+        assert!(pacer.try_acquire().is_some());
+    }
+}
@@ -1,43 +1,189 @@
 use std::fmt;
+use std::ops::Deref;
 
+use serde::Serializer;
 use serde_json::{Value, from_str, to_string};
 use smartfox_c::packet;
 
-use error::{Result, ResultExt};
+use error::{Error, ErrorKind, Result, ResultExt};
+use data::World;
+use map_scan::{Area, TILE_SIZE};
+use connection::ConnectionConfig;
+use data_extractors::gbd::Gbd;
+use data_extractors::gdi::Gdi;
+use data_extractors::map::Gaa;
+
+/// Result of the game login
+///
+/// Only 0 is known to mean success. The meaning of the other codes isn't documented anywhere,
+/// so they are kept as is.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LoginStatus {
+    /// Logged in
+    Ok,
+    /// Rejected with the given status code
+    Other(i64),
+}
+
+impl LoginStatus {
+    /// Decode the status code of a lli packet
+    pub fn from_code(code: i64) -> Self {
+        match code {
+            0 => LoginStatus::Ok,
+            code => LoginStatus::Other(code),
+        }
+    }
+
+    /// The status code of a lli packet
+    pub fn code(&self) -> i64 {
+        match *self {
+            LoginStatus::Ok => 0,
+            LoginStatus::Other(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for LoginStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoginStatus::Ok => write!(f, "logged in"),
+            LoginStatus::Other(code) => write!(f, "rejected with status {}", code),
+        }
+    }
+}
+
+/// The header of a game packet: `%xt%<cmd>%<room>%<status>%<data>%`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// Command name, for example "gaa"
+    pub command: String,
+    /// Room or internal id, usually 1
+    pub room: i64,
+    /// Return code, 0 when the request succeeded
+    pub status: i64,
+}
+
+impl Header {
+    /// Parse the header of a game packet
+    pub fn parse(data: &str) -> Option<Header> {
+        let mut fields = data.split('%');
+        if fields.next() != Some("") || fields.next() != Some("xt") {
+            return None;
+        }
+        let command = fields.next()?;
+        let room = fields.next()?.parse().ok()?;
+        let status = fields.next()?.parse().ok()?;
+        Some(Header {
+            command: command.to_string(),
+            room: room,
+            status: status,
+        })
+    }
+
+    /// Whether the server reported success
+    pub fn is_ok(&self) -> bool {
+        self.status == 0
+    }
+}
+
+/// The data part of a game packet as json, a json string when it isn't json
+fn json_data(data: &str) -> Value {
+    let data = data.trim_matches('%');
+    from_str(data).unwrap_or_else(|_| Value::String(data.to_string()))
+}
+
+/// Decoded packet data together with the header and the json it was decoded from
+///
+/// Derefs to the decoded data. Use `raw` for fields the decoded data doesn't contain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Payload<T> {
+    /// Packet header
+    pub header: Header,
+    /// Decoded data
+    pub data: T,
+    /// The json data as received
+    pub raw: Value,
+}
+
+impl<T> Payload<T> {
+    /// Decode json data
+    pub fn decode<F>(header: Header, raw: Value, decode: F) -> Result<Self>
+    where
+        F: FnOnce(Value) -> Result<T>,
+    {
+        Ok(Payload {
+            header: header,
+            data: decode(raw.clone())?,
+            raw: raw,
+        })
+    }
+}
+
+impl Payload<Value> {
+    /// Keep the json data as it is
+    pub fn json(header: Header, raw: Value) -> Self {
+        Payload {
+            header: header,
+            data: raw.clone(),
+            raw: raw,
+        }
+    }
+}
+
+impl<T> Deref for Payload<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.data
+    }
+}
 
 /// A server returned packet of data.
+///
+/// Packets with a known structure are decoded, the others contain the json data.
+///
+/// kpi, gam, sei, irc, nfo and core_gpi stay untyped: the importer doesn't read them and there is
+/// no capture of their data to derive a structure from. Guessing one would make the whole packet
+/// fail to parse when the guess is wrong. `Payload::raw` and the json `data` give access to them.
 #[derive(Clone, PartialEq)]
 pub enum ServerPacket {
     /// Unrecognized data
     Data(String, String),
 
-    /// Kpi packet
-    Kpi(String),
+    /// Login result
+    Lli(LoginStatus),
+
+    /// Kpi packet, untyped
+    Kpi(Payload<Value>),
 
-    /// Gam packet
-    Gam(String),
+    /// Gam packet, untyped
+    Gam(Payload<Value>),
 
     /// Main data source.
     /// Send by the server when you login.
-    Gbd(Value),
+    Gbd(Payload<Gbd>),
 
     /// Castle information of a specific user.
-    Gdi(String),
+    Gdi(Payload<Gdi>),
 
-    /// Unknown kind of data
-    Sei(String),
+    /// Unknown kind of data, untyped
+    Sei(Payload<Value>),
 
-    /// Some kind of keepalive data.
-    Irc(String),
+    /// Some kind of keepalive data, untyped
+    Irc(Payload<Value>),
 
-    /// Server info
-    Nfo(String),
+    /// Server info, untyped
+    Nfo(Payload<Value>),
 
-    /// Get player info
-    CoreGpi(String),
+    /// Get player info, untyped
+    CoreGpi(Payload<Value>),
 
     /// Map info
-    Gaa(String),
+    Gaa(Payload<Gaa>),
+
+    /// Answer with a non-zero status to a request with a decoded answer, like gaa or gdi.
+    /// The data is not decoded, it usually describes the error.
+    Failed(Payload<Value>),
 
     /// Empty packet.
     None,
@@ -46,40 +192,132 @@ pub enum ServerPacket {
 impl ServerPacket {
     /// Create a packet from text.
     /// Returns ServerPacket::Data when it does not recognize the data.
+    ///
+    /// # Errors
+    ///
+    /// * `ErrorKind::InvalidPacket` when it isn't a SmartFoxServer packet
+    /// * Decoding errors when the data of a gbd, gdi or gaa packet has a different structure
     pub fn new(original_data: String) -> Result<Self> {
-        let pkt = original_data.parse::<packet::Packet>().unwrap();
+        let pkt = match original_data.parse::<packet::Packet>() {
+            Ok(pkt) => pkt,
+            Err(_) => {
+                return Err(
+                    ErrorKind::InvalidPacket(format!("Cant parse {}", original_data).into()).into(),
+                )
+            }
+        };
         Ok(if !pkt.name.is_empty() {
+            let data = json_data(&pkt.data);
+            let header = Header::parse(&original_data).unwrap_or_else(|| Header {
+                command: pkt.name.to_string(),
+                room: -1,
+                status: -1,
+            });
             match &*pkt.name {
-                "kpi"      => ServerPacket::Kpi    (pkt.data.to_string()),
-                "gam"      => ServerPacket::Gam    (pkt.data.to_string()),
-                "gbd"      => ServerPacket::Gbd    (from_str(&pkt.data).chain_err(|| format!("Failed to parse gbd packet: {}", &pkt.data))?),
-                "gdi"      => ServerPacket::Gdi    (pkt.data.to_string()),
-                "irc"      => ServerPacket::Irc    (pkt.data.to_string()),
-                "sei"      => ServerPacket::Sei    (pkt.data.to_string()),
-                "nfo"      => ServerPacket::Nfo    (pkt.data.to_string()),
-                "core_gpi" => ServerPacket::CoreGpi(pkt.data.to_string()),
-                "gaa"      => ServerPacket::Gaa    (pkt.data.to_string()),
+                "lli"      => ServerPacket::Lli    (LoginStatus::from_code(header.status)),
+                "gbd" | "gdi" | "gaa" if !header.is_ok() => ServerPacket::Failed(Payload::json(header, data)),
+                "kpi"      => ServerPacket::Kpi    (Payload::json(header, data)),
+                "gam"      => ServerPacket::Gam    (Payload::json(header, data)),
+                "gbd"      => ServerPacket::Gbd    (Payload::decode(header, data, Gbd::parse_val).chain_err(|| format!("Failed to parse gbd packet: {}", &pkt.data))?),
+                "gdi"      => ServerPacket::Gdi    (Payload::decode(header, data, Gdi::parse_val).chain_err(|| format!("Failed to parse gdi packet: {}", &pkt.data))?),
+                "irc"      => ServerPacket::Irc    (Payload::json(header, data)),
+                "sei"      => ServerPacket::Sei    (Payload::json(header, data)),
+                "nfo"      => ServerPacket::Nfo    (Payload::json(header, data)),
+                "core_gpi" => ServerPacket::CoreGpi(Payload::json(header, data)),
+                "gaa"      => ServerPacket::Gaa    (Payload::decode(header, data, Gaa::parse_val).chain_err(|| format!("Failed to parse gaa packet: {}", &pkt.data))?),
                 _          => ServerPacket::Data   (pkt.name.to_string(), pkt.data.to_string())
             }
         } else {
             ServerPacket::Data("".to_string(), original_data.to_string())
         })
     }
+
+    /// Command name of the packet, for example "gaa"
+    pub fn command(&self) -> &str {
+        match *self {
+            ServerPacket::Data(ref name, _) => name,
+            ServerPacket::Lli(_) => "lli",
+            ServerPacket::Kpi(_) => "kpi",
+            ServerPacket::Gam(_) => "gam",
+            ServerPacket::Gbd(_) => "gbd",
+            ServerPacket::Gdi(_) => "gdi",
+            ServerPacket::Sei(_) => "sei",
+            ServerPacket::Irc(_) => "irc",
+            ServerPacket::Nfo(_) => "nfo",
+            ServerPacket::CoreGpi(_) => "core_gpi",
+            ServerPacket::Gaa(_) => "gaa",
+            ServerPacket::Failed(ref data) => &data.header.command,
+            ServerPacket::None => "",
+        }
+    }
+
+    /// The packet header, None for login results and unrecognized packets
+    pub fn header(&self) -> Option<&Header> {
+        match *self {
+            ServerPacket::Kpi(ref data) |
+            ServerPacket::Gam(ref data) |
+            ServerPacket::Sei(ref data) |
+            ServerPacket::Irc(ref data) |
+            ServerPacket::Nfo(ref data) |
+            ServerPacket::CoreGpi(ref data) |
+            ServerPacket::Failed(ref data) => Some(&data.header),
+            ServerPacket::Gbd(ref data) => Some(&data.header),
+            ServerPacket::Gdi(ref data) => Some(&data.header),
+            ServerPacket::Gaa(ref data) => Some(&data.header),
+            ServerPacket::Data(..) | ServerPacket::Lli(_) | ServerPacket::None => None,
+        }
+    }
+
+    /// The status code the server returned
+    pub fn status(&self) -> Option<i64> {
+        match *self {
+            ServerPacket::Lli(status) => Some(status.code()),
+            _ => self.header().map(|header| header.status),
+        }
+    }
+
+    /// The error the server reported, None when the packet isn't an error response
+    pub fn error(&self) -> Option<Error> {
+        match *self {
+            ServerPacket::Failed(ref data) => Some(
+                ErrorKind::ServerError(data.header.command.clone(), data.header.status).into(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// The area of a map packet, when the server echoed it
+    pub fn gaa_area(&self) -> Option<Area> {
+        match *self {
+            ServerPacket::Gaa(ref gaa) => gaa.area,
+            _ => None,
+        }
+    }
+}
+
+/// Json as text, strings without quotes
+fn json_text(data: &Value) -> String {
+    match *data {
+        Value::String(ref data) => data.clone(),
+        ref data => to_string(data).unwrap(),
+    }
 }
 
 impl fmt::Debug for ServerPacket {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let (description, name, data): (&'static str, String, String) = match self.clone() {
             ServerPacket::Data   (name, data) => ("unknown type"  , name      , data),
-            ServerPacket::Kpi    (data)       => (""              , "kpi".to_string()      , data),
-            ServerPacket::Gam    (data)       => (""              , "gam".to_string()      , data),
-	        ServerPacket::Gbd    (data)       => (""              , "gbd".to_string()      , to_string(&data).unwrap()),
-            ServerPacket::Gdi    (data)       => (""              , "gdi".to_string()      , data),
-            ServerPacket::Sei    (data)       => (""              , "sei".to_string()      , data),
-            ServerPacket::Irc    (data)       => (""              , "irc".to_string()      , data),
-            ServerPacket::Nfo    (data)       => ("serverinfo"    , "nfo".to_string()      , data),
-            ServerPacket::CoreGpi(data)       => ("getplayerinfo" , "core_gpi".to_string() , data),
-            ServerPacket::Gaa    (data)       => ("mapinfo"       , "gaa".to_string()      , data),
+            ServerPacket::Lli    (status)     => ("login"         , "lli".to_string()      , status.to_string()),
+            ServerPacket::Kpi    (data)       => (""              , "kpi".to_string()      , json_text(&data.raw)),
+            ServerPacket::Gam    (data)       => (""              , "gam".to_string()      , json_text(&data.raw)),
+            ServerPacket::Gbd    (data)       => (""              , "gbd".to_string()      , json_text(&data.raw)),
+            ServerPacket::Gdi    (data)       => (""              , "gdi".to_string()      , json_text(&data.raw)),
+            ServerPacket::Sei    (data)       => (""              , "sei".to_string()      , json_text(&data.raw)),
+            ServerPacket::Irc    (data)       => (""              , "irc".to_string()      , json_text(&data.raw)),
+            ServerPacket::Nfo    (data)       => ("serverinfo"    , "nfo".to_string()      , json_text(&data.raw)),
+            ServerPacket::CoreGpi(data)       => ("getplayerinfo" , "core_gpi".to_string() , json_text(&data.raw)),
+            ServerPacket::Gaa    (data)       => ("mapinfo"       , "gaa".to_string()      , json_text(&data.raw)),
+            ServerPacket::Failed (data)       => ("error"         , data.header.command    , format!("status {} {}", data.header.status, json_text(&data.raw))),
             ServerPacket::None                => ("none"          , "".to_string()         , "".to_string()),
         };
         write!(
@@ -92,21 +330,163 @@ impl fmt::Debug for ServerPacket {
     }
 }
 
+/// Request for a part of the world map
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GaaRequest {
+    /// World
+    #[serde(rename = "KID", serialize_with = "serialize_world")]
+    pub world: World,
+    /// Left
+    #[serde(rename = "AX1")]
+    pub x1: u64,
+    /// Top
+    #[serde(rename = "AY1")]
+    pub y1: u64,
+    /// Right
+    #[serde(rename = "AX2")]
+    pub x2: u64,
+    /// Bottom
+    #[serde(rename = "AY2")]
+    pub y2: u64,
+}
+
+fn serialize_world<S>(world: &World, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_u8(*world as u8)
+}
+
+impl GaaRequest {
+    /// Create a request for `area` of `world`
+    ///
+    /// Fails when the area is bigger than a tile. The size of the map isn't checked, the scanned
+    /// area is checked by `Area::parse_with_size`.
+    pub fn new(world: World, area: Area) -> Result<Self> {
+        let request = GaaRequest {
+            world: world,
+            x1: area.x1,
+            y1: area.y1,
+            x2: area.x2,
+            y2: area.y2,
+        };
+        request.validate()?;
+        Ok(request)
+    }
+
+    /// Check that the requested area is a valid tile
+    pub fn validate(&self) -> Result<()> {
+        if self.x1 > self.x2 || self.y1 > self.y2 {
+            return Err(ErrorKind::InvalidRequest(
+                format!("gaa corners swapped: {:?}", self).into(),
+            ).into());
+        }
+        if self.x2 - self.x1 >= TILE_SIZE || self.y2 - self.y1 >= TILE_SIZE {
+            return Err(ErrorKind::InvalidRequest(
+                format!("gaa bigger than {}x{}: {:?}", TILE_SIZE, TILE_SIZE, self).into(),
+            ).into());
+        }
+        Ok(())
+    }
+
+    /// The requested area
+    pub fn area(&self) -> Area {
+        Area {
+            x1: self.x1,
+            y1: self.y1,
+            x2: self.x2,
+            y2: self.y2,
+        }
+    }
+}
+
 /// A client send packet of data
-#[derive(Debug)]
+#[derive(Clone, PartialEq)]
 pub enum ClientPacket {
+    /// Login
+    Lli { username: String, password: String },
+
+    /// Keepalive
+    Pin,
+
     /// Ask for user castles
     Gdi(u64),
 
     /// Ask for world map
-    Gaa(String),
+    Gaa(GaaRequest),
 }
 
 impl ClientPacket {
-    pub fn to_raw_data(&self) -> String {
+    /// Command of the packet the server answers with
+    pub fn reply_command(&self) -> Option<&'static str> {
         match *self {
-            ClientPacket::Gdi(uid) => format!("%xt%EmpireEx_11%gdi%1%{{\"PID\":{}}}%", uid),
-            ClientPacket::Gaa(ref data) => format!("%xt%EmpireEx_11%gaa%1%{}%", data),
+            ClientPacket::Lli { .. } => Some("lli"),
+            ClientPacket::Pin => None,
+            ClientPacket::Gdi(_) => Some("gdi"),
+            ClientPacket::Gaa(_) => Some("gaa"),
+        }
+    }
+
+    pub fn to_raw_data(&self, config: &ConnectionConfig) -> String {
+        match *self {
+            ClientPacket::Lli {
+                ref username,
+                ref password,
+            } => {
+                #[derive(Serialize)]
+                #[allow(non_snake_case)]
+                struct Lli<'a> {
+                    CONM: u64,
+                    RTM: u64,
+                    ID: u64,
+                    PW: &'a str,
+                    NOM: &'a str,
+                    LANG: &'a str,
+                    AID: &'a str,
+                    REF: &'a str,
+                    KID: &'a str,
+                    DID: &'a str,
+                    FID: Option<()>,
+                    FTK: Option<()>,
+                    FAID: Option<()>,
+                }
+
+                let lli = Lli {
+                    CONM: config.conm,
+                    RTM: config.rtm,
+                    ID: 0,
+                    PW: password,
+                    NOM: username,
+                    LANG: &config.language,
+                    AID: &config.aid,
+                    REF: &config.referer,
+                    KID: "",
+                    DID: "",
+                    FID: None,
+                    FTK: None,
+                    FAID: None,
+                };
+                format!("%xt%{}%lli%1%{}%", config.zone, to_string(&lli).unwrap())
+            }
+            ClientPacket::Pin => format!("%xt%{}%pin%1%<RoundHouseKick>%", config.zone),
+            ClientPacket::Gdi(uid) => format!("%xt%{}%gdi%1%{{\"PID\":{}}}%", config.zone, uid),
+            ClientPacket::Gaa(ref request) => {
+                format!("%xt%{}%gaa%1%{}%", config.zone, to_string(request).unwrap())
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ClientPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            // Never log the password
+            ClientPacket::Lli { ref username, .. } => {
+                write!(f, "Lli {{ username: {:?} }}", username)
+            }
+            ClientPacket::Pin => write!(f, "Pin"),
+            ClientPacket::Gdi(uid) => write!(f, "Gdi({:?})", uid),
+            ClientPacket::Gaa(ref request) => write!(f, "Gaa({:?})", request),
         }
     }
 }
@@ -119,12 +499,33 @@ mod test {
     fn parse_server_packet() {
         assert_eq!(
             ServerPacket::new("%xt%lli%1%0%".to_string()).unwrap(),
-            ServerPacket::Data("lli".to_string(), "".to_string())
+            ServerPacket::Lli(LoginStatus::Ok)
+        );
+        assert_eq!(
+            ServerPacket::new("%xt%lli%1%11%".to_string()).unwrap(),
+            ServerPacket::Lli(LoginStatus::Other(11))
         );
         assert_eq!(
-            ServerPacket::new(r#"%xt%gbd%1%0%{"gpi":{"UID":0}}%"#.to_string()).unwrap(),
-            ServerPacket::Gbd(from_str(r#"{"gpi":{"UID":0}}"#).unwrap())
+            ServerPacket::new("%xt%lli%1%42%".to_string()).unwrap(),
+            ServerPacket::Lli(LoginStatus::Other(42))
         );
+        let gbd = match ServerPacket::new(r#"%xt%gbd%1%0%{"gpi":{"UID":0}}%"#.to_string()) {
+            Ok(ServerPacket::Gbd(gbd)) => gbd,
+            packet => panic!("{:?}", packet),
+        };
+        assert_eq!(gbd.raw, from_str::<Value>(r#"{"gpi":{"UID":0}}"#).unwrap());
+        assert!(gbd.ain.is_empty());
+
+        let gdi = r#"%xt%gdi%1%0%{"gcl":{"C":[{"KID":2,"AI":[{"AI":[1,5,6,42,7,"Frost"]}]}]}}%"#;
+        match ServerPacket::new(gdi.to_string()).unwrap() {
+            ServerPacket::Gdi(gdi) => {
+                assert_eq!(gdi.castles.len(), 1);
+                assert_eq!(gdi.castles[0].id, 42);
+                assert_eq!(gdi.castles[0].name, Some("Frost".to_string()));
+                assert_eq!(gdi.castles[0].world, Some(World::Ice));
+            }
+            packet => panic!("{:?}", packet),
+        }
     }
 
     #[test]
@@ -145,20 +546,153 @@ mod test {
     #[test]
     fn display_server_packet() {
         assert_eq!(
-            format!("{:?}", ServerPacket::Irc("dsimoreoib".to_string())),
+            format!(
+                "{:?}",
+                ServerPacket::Irc(Payload::json(
+                    Header::parse("%xt%irc%1%0%").unwrap(),
+                    Value::String("dsimoreoib".to_string()),
+                ))
+            ),
             "              (irc      ) ( dsimoreoib ... )".to_string()
         );
     }
 
+    #[test]
+    fn parse_header() {
+        assert_eq!(
+            Header::parse(r#"%xt%gaa%1%0%{"KID":0}%"#),
+            Some(Header {
+                command: "gaa".to_string(),
+                room: 1,
+                status: 0,
+            })
+        );
+        assert_eq!(Header::parse("%xt%gdi%-1%114%").unwrap().status, 114);
+        assert_eq!(Header::parse("%xt%gdi%1%"), None);
+        assert_eq!(Header::parse("<msg t='sys'></msg>"), None);
+    }
+
+    #[test]
+    fn failed_server_packet() {
+        let gaa = ServerPacket::new("%xt%gaa%1%114%{}%".to_string()).unwrap();
+        assert_eq!(gaa.command(), "gaa");
+        assert_eq!(gaa.status(), Some(114));
+        match gaa.error() {
+            Some(Error(ErrorKind::ServerError(ref command, 114), _)) if command == "gaa" => {}
+            err => panic!("{:?}", err),
+        }
+
+        let gdi = ServerPacket::new("%xt%gdi%1%0%{}%".to_string()).unwrap();
+        assert_eq!(gdi.status(), Some(0));
+        assert!(gdi.error().is_none());
+        assert_eq!(
+            ServerPacket::new("%xt%lli%1%11%".to_string()).unwrap().status(),
+            Some(11)
+        );
+    }
+
     #[test]
     fn serialize_client_packet() {
+        use connection::DUTCH_CONFIG;
+
         assert_eq!(
-            ClientPacket::Gdi(10).to_raw_data(),
+            ClientPacket::Gdi(10).to_raw_data(&DUTCH_CONFIG),
             "%xt%EmpireEx_11%gdi%1%{\"PID\":10}%".to_string()
         );
         assert_eq!(
-            ClientPacket::Gaa("agreverebcd".to_string()).to_raw_data(),
-            "%xt%EmpireEx_11%gaa%1%agreverebcd%".to_string()
+            ClientPacket::Pin.to_raw_data(&DUTCH_CONFIG),
+            "%xt%EmpireEx_11%pin%1%<RoundHouseKick>%".to_string()
+        );
+        assert_eq!(
+            ClientPacket::Gaa(GaaRequest {
+                world: World::Grass,
+                x1: 546,
+                y1: 676,
+                x2: 558,
+                y2: 688,
+            }).to_raw_data(&DUTCH_CONFIG),
+            "%xt%EmpireEx_11%gaa%1%{\"KID\":0,\"AX1\":546,\"AY1\":676,\"AX2\":558,\"AY2\":688}%"
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn serialize_login_packet() {
+        let config = ConnectionConfig {
+            zone: "EmpireEx_2".to_string(),
+            language: "de".to_string(),
+            ..ConnectionConfig::default()
+        };
+        let raw = ClientPacket::Lli {
+            username: "some \"user\"".to_string(),
+            password: "secret".to_string(),
+        }.to_raw_data(&config);
+        assert!(raw.starts_with("%xt%EmpireEx_2%lli%1%{"));
+        assert!(raw.ends_with("}%"));
+
+        let json = raw.trim_left_matches("%xt%EmpireEx_2%lli%1%").trim_right_matches('%');
+        let json: Value = from_str(json).unwrap();
+        assert_eq!(json["NOM"], Value::String("some \"user\"".to_string()));
+        assert_eq!(json["PW"], Value::String("secret".to_string()));
+        assert_eq!(json["LANG"], Value::String("de".to_string()));
+        assert_eq!(json["CONM"], Value::from(config.conm));
+        assert_eq!(config.smartfox_password(), "1455712286016%de%");
+    }
+
+    #[test]
+    fn gaa_request_round_trip() {
+        let request = GaaRequest {
+            world: World::Fire,
+            x1: 338,
+            y1: 806,
+            x2: 350,
+            y2: 818,
+        };
+        let json = to_string(&request).unwrap();
+        assert_eq!(from_str::<GaaRequest>(&json).unwrap(), request);
+        assert_eq!(
+            from_str::<GaaRequest>(r#"{"AX2":350,"KID":3,"AY1":806,"AY2":818,"AX1":338}"#)
+                .unwrap(),
+            request
+        );
+    }
+
+    #[test]
+    fn validate_gaa_request() {
+        let area = Area {
+            x1: 546,
+            y1: 676,
+            x2: 558,
+            y2: 688,
+        };
+        assert!(GaaRequest::new(World::Grass, area).is_ok());
+        assert!(
+            GaaRequest::new(
+                World::Grass,
+                Area {
+                    x2: 559,
+                    ..area
+                },
+            ).is_err()
+        );
+        assert!(
+            GaaRequest::new(
+                World::Grass,
+                Area {
+                    x1: 676,
+                    y1: 546,
+                    ..area
+                },
+            ).is_err()
+        );
+        assert!(
+            GaaRequest::new(
+                World::Grass,
+                Area {
+                    y2: 690,
+                    ..area
+                },
+            ).is_err()
         );
     }
 }
@@ -0,0 +1,192 @@
+use std::fs::File;
+use std::path::Path;
+use std::time::Duration;
+
+use serde_json::{self, Map, Value};
+
+use error::{Result, ResultExt};
+
+/// A game packet received by a dummy server
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    /// Command, for example "gaa"
+    pub command: String,
+    /// Json data of the packet, a string when it isn't json
+    pub data: Value,
+}
+
+impl Request {
+    /// Parse a packet send by the client: `%xt%<zone>%<cmd>%1%<data>%`
+    ///
+    /// Returns None for other kinds of packets.
+    pub fn parse(packet: &str) -> Option<Self> {
+        let parts = packet.splitn(6, '%').collect::<Vec<_>>();
+        if parts.len() != 6 || !parts[0].is_empty() || parts[1] != "xt" {
+            return None;
+        }
+        let data = parts[5];
+        let data = if data.ends_with('%') {
+            &data[..data.len() - 1]
+        } else {
+            data
+        };
+        Some(Request {
+            command: parts[3].to_string(),
+            data: serde_json::from_str(data).unwrap_or_else(|_| Value::String(data.to_string())),
+        })
+    }
+}
+
+/// Answer of a dummy server to a request
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Response {
+    /// Packets to send, without zero terminator
+    pub packets: Vec<String>,
+    /// Time to wait before sending the packets
+    pub delay: Duration,
+    /// Close the connection after sending the packets
+    pub disconnect: bool,
+}
+
+/// Decides how a dummy server answers requests
+pub trait Responder: Send {
+    /// Answer a request
+    fn respond(&mut self, request: &Request) -> Response;
+}
+
+impl<R: Responder + ?Sized> Responder for Box<R> {
+    fn respond(&mut self, request: &Request) -> Response {
+        (**self).respond(request)
+    }
+}
+
+/// Canned response for matching requests
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rule {
+    /// Command of the request, for example "gdi"
+    pub command: String,
+    /// Fields the json data of the request must have, for example `{"PID": 42}`
+    #[serde(default, rename = "match")]
+    pub matches: Map<String, Value>,
+    /// Packets to send
+    #[serde(default)]
+    pub packets: Vec<String>,
+    /// Milliseconds to wait before answering
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// Close the connection after answering
+    #[serde(default)]
+    pub disconnect: bool,
+    /// Only use this rule for the first `times` matching requests
+    #[serde(default)]
+    pub times: Option<u32>,
+}
+
+impl Rule {
+    /// Does the rule apply to the request?
+    pub fn applies_to(&self, request: &Request) -> bool {
+        self.command == request.command && self.times != Some(0) &&
+            self.matches.iter().all(|(key, value)| {
+                request.data.get(key) == Some(value)
+            })
+    }
+}
+
+/// Dummy server script, read from a json file
+///
+/// ```json
+/// {"rules": [
+///     {"command": "lli", "packets": ["%xt%lli%1%0%", "%xt%gbd%1%0%{...}%"]},
+///     {"command": "gaa", "match": {"AX1": 0, "AY1": 0}, "packets": ["..."], "delay_ms": 500},
+///     {"command": "gdi", "match": {"PID": 42}, "disconnect": true, "times": 1}
+/// ]}
+/// ```
+///
+/// The first matching rule answers a request. Requests without matching rule are not
+/// answered, except for the login, which succeeds.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Scenario {
+    /// Rules in the order they are tried
+    pub rules: Vec<Rule>,
+}
+
+impl Scenario {
+    /// Read a scenario file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path).chain_err(|| "Cant open scenario file")?;
+        serde_json::from_reader(file).chain_err(|| "Cant parse scenario file")
+    }
+}
+
+impl Responder for Scenario {
+    fn respond(&mut self, request: &Request) -> Response {
+        match self.rules.iter_mut().find(|rule| rule.applies_to(request)) {
+            Some(rule) => {
+                if let Some(ref mut times) = rule.times {
+                    *times -= 1;
+                }
+                Response {
+                    packets: rule.packets.clone(),
+                    delay: Duration::from_millis(rule.delay_ms),
+                    disconnect: rule.disconnect,
+                }
+            }
+            None if request.command == "lli" => Response {
+                packets: vec!["%xt%lli%1%0%".to_string()],
+                ..Response::default()
+            },
+            None => Response::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request() {
+        assert_eq!(
+            Request::parse(r#"%xt%EmpireEx_11%gdi%1%{"PID":42}%"#),
+            Some(Request {
+                command: "gdi".to_string(),
+                data: serde_json::from_str(r#"{"PID":42}"#).unwrap(),
+            })
+        );
+        assert_eq!(
+            Request::parse("%xt%EmpireEx_11%pin%1%<RoundHouseKick>%")
+                .unwrap()
+                .data,
+            Value::String("<RoundHouseKick>".to_string())
+        );
+        assert_eq!(Request::parse("<msg t='sys'></msg>"), None);
+    }
+
+    #[test]
+    fn respond_to_matching_rule() {
+        let mut scenario: Scenario = serde_json::from_str(
+            r#"{"rules": [
+                {"command": "gdi", "match": {"PID": 42}, "disconnect": true, "times": 1},
+                {"command": "gdi", "match": {"PID": 42}, "packets": ["gdi 42"], "delay_ms": 10}
+            ]}"#,
+        ).unwrap();
+        let request = Request::parse(r#"%xt%EmpireEx_11%gdi%1%{"PID":42}%"#).unwrap();
+
+        let first = scenario.respond(&request);
+        assert!(first.disconnect && first.packets.is_empty());
+        assert_eq!(
+            scenario.respond(&request),
+            Response {
+                packets: vec!["gdi 42".to_string()],
+                delay: Duration::from_millis(10),
+                disconnect: false,
+            }
+        );
+
+        let other = Request::parse(r#"%xt%EmpireEx_11%gdi%1%{"PID":43}%"#).unwrap();
+        assert_eq!(scenario.respond(&other), Response::default());
+
+        let lli = Request::parse(r#"%xt%EmpireEx_11%lli%1%{"NOM":"test"}%"#).unwrap();
+        assert_eq!(scenario.respond(&lli).packets, vec!["%xt%lli%1%0%"]);
+    }
+}
@@ -0,0 +1,140 @@
+use std::fs::File;
+use std::path::Path;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use serde_json::de::from_reader;
+
+use error::{ErrorKind, Result, ResultExt};
+use connection::{ConnectionConfig, DUTCH_CONFIG, LOCAL_CONFIG};
+
+/// A game server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Server {
+    /// Name used to select the server
+    pub name: String,
+    /// Host and port, for example "37.48.88.129:80"
+    pub address: String,
+    /// Zone, language and client settings
+    #[serde(default)]
+    pub config: ConnectionConfig,
+}
+
+impl Server {
+    /// Resolve the address of the server
+    pub fn socket_addr(&self) -> Result<SocketAddr> {
+        self.address
+            .to_socket_addrs()
+            .chain_err(|| format!("Cant resolve address of server {}", self.name))?
+            .next()
+            .ok_or_else(|| {
+                format!("No address found for server {}", self.name).into()
+            })
+    }
+}
+
+/// List of known servers
+#[derive(Debug, Clone)]
+pub struct ServerRegistry {
+    servers: Vec<Server>,
+}
+
+impl Default for ServerRegistry {
+    /// The builtin servers: `dutch` and `local`
+    fn default() -> Self {
+        ServerRegistry {
+            servers: vec![
+                Server {
+                    name: "dutch".to_string(),
+                    address: "37.48.88.129:80".to_string(),
+                    config: DUTCH_CONFIG.clone(),
+                },
+                Server {
+                    name: "local".to_string(),
+                    address: "127.0.0.1:8081".to_string(),
+                    config: LOCAL_CONFIG.clone(),
+                },
+            ],
+        }
+    }
+}
+
+impl ServerRegistry {
+    /// The builtin servers together with the servers from a config file
+    ///
+    /// The file contains a json array of servers:
+    ///
+    /// ```json
+    /// [{"name": "german", "address": "example.com:443", "config": {"zone": "EmpireEx_2", "language": "de"}}]
+    /// ```
+    ///
+    /// Servers in the file replace builtin servers with the same name.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).chain_err(|| {
+            format!("Cant open server config {}", path.display())
+        })?;
+        let servers: Vec<Server> = from_reader(file).chain_err(|| {
+            format!("Cant parse server config {}", path.display())
+        })?;
+
+        let mut registry = ServerRegistry::default();
+        for server in servers {
+            registry.add(server);
+        }
+        Ok(registry)
+    }
+
+    /// Add a server, replacing the server with the same name
+    pub fn add(&mut self, server: Server) {
+        self.servers.retain(|other| other.name != server.name);
+        self.servers.push(server);
+    }
+
+    /// Find a server by name
+    pub fn get(&self, name: &str) -> Result<&Server> {
+        self.servers
+            .iter()
+            .find(|server| server.name == name)
+            .ok_or_else(|| ErrorKind::UnknownServer(name.to_string()).into())
+    }
+
+    /// The names of all servers
+    pub fn names(&self) -> Vec<&str> {
+        self.servers.iter().map(|server| &*server.name).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_servers() {
+        let registry = ServerRegistry::default();
+        assert_eq!(registry.names(), vec!["dutch", "local"]);
+        assert_eq!(registry.get("dutch").unwrap().config.zone, "EmpireEx_11");
+        assert_eq!(
+            registry.get("local").unwrap().socket_addr().unwrap(),
+            "127.0.0.1:8081".parse().unwrap()
+        );
+        assert!(registry.get("atlantis").is_err());
+    }
+
+    #[test]
+    fn parse_server() {
+        let server: Server = ::serde_json::from_str(
+            r#"{"name":"german","address":"127.0.0.1:443","config":{"zone":"EmpireEx_2","language":"de"}}"#,
+        ).unwrap();
+        assert_eq!(server.config.zone, "EmpireEx_2");
+        assert_eq!(server.config.language, "de");
+        assert_eq!(server.config.rtm, DUTCH_CONFIG.rtm);
+
+        let mut registry = ServerRegistry::default();
+        registry.add(Server {
+            name: "dutch".to_string(),
+            ..server
+        });
+        assert_eq!(registry.names(), vec!["local", "dutch"]);
+        assert_eq!(registry.get("dutch").unwrap().config.language, "de");
+    }
+}
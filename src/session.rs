@@ -0,0 +1,289 @@
+use std::io;
+use std::thread;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use slog::Logger;
+
+use error::{Error, ErrorKind, Result};
+use connection::{Connection, ConnectionConfig, PacketSender};
+use packet::{ServerPacket, ClientPacket};
+use pacer::Pacer;
+use capture::{Recorder, Replay};
+use dead_letter::DeadLetters;
+
+/// Default time without sending after which a keepalive is send
+pub const DEFAULT_KEEPALIVE: u64 = 30;
+
+/// Default amount of reconnect attempts after the connection dropped
+pub const DEFAULT_MAX_RECONNECTS: u32 = 5;
+
+/// Long running session which keeps the connection alive and reconnects when it drops
+pub struct Session {
+    /// None when replaying a capture
+    server: Option<SocketAddr>,
+    config: ConnectionConfig,
+    username: String,
+    password: String,
+    recorder: Option<Recorder>,
+    con: Connection,
+    keepalive: Option<Duration>,
+    deadline: Option<Duration>,
+    pacer: Option<Pacer>,
+    /// Shared by all connections of the session
+    dead_letters: DeadLetters,
+    max_reconnects: u32,
+    reconnects: u32,
+    logger: Logger,
+}
+
+impl Session {
+    /// Connect and login
+    pub fn connect(
+        server: SocketAddr,
+        config: ConnectionConfig,
+        un: &str,
+        pw: &str,
+        logger: Logger,
+    ) -> Result<Self> {
+        Session::recording(server, config, un, pw, None, logger)
+    }
+
+    /// Connect and login, all packets of all connections are written to `recorder`
+    pub fn recording(
+        server: SocketAddr,
+        config: ConnectionConfig,
+        un: &str,
+        pw: &str,
+        recorder: Option<Recorder>,
+        logger: Logger,
+    ) -> Result<Self> {
+        let con = Connection::recording(
+            server,
+            config.clone(),
+            un,
+            pw,
+            recorder.clone(),
+            logger.clone(),
+        )?;
+        Ok(Session::with_connection(
+            Some(server),
+            config,
+            un,
+            pw,
+            recorder,
+            con,
+            logger,
+        ))
+    }
+
+    /// Replay a capture, see `Connection::replay`
+    ///
+    /// There is nothing to reconnect to, so the session ends with the capture.
+    pub fn replay(replay: Replay, config: ConnectionConfig, logger: Logger) -> Result<Self> {
+        let con = Connection::replay(replay, config.clone(), logger.clone())?;
+        Ok(Session::with_connection(None, config, "", "", None, con, logger))
+    }
+
+    fn with_connection(
+        server: Option<SocketAddr>,
+        config: ConnectionConfig,
+        un: &str,
+        pw: &str,
+        recorder: Option<Recorder>,
+        mut con: Connection,
+        logger: Logger,
+    ) -> Self {
+        let keepalive = Some(Duration::new(DEFAULT_KEEPALIVE, 0));
+        con.set_keepalive(keepalive);
+        let dead_letters = con.dead_letters().clone();
+        Session {
+            server: server,
+            config: config,
+            username: un.to_string(),
+            password: pw.to_string(),
+            recorder: recorder,
+            con: con,
+            keepalive: keepalive,
+            deadline: None,
+            pacer: None,
+            dead_letters: dead_letters,
+            max_reconnects: DEFAULT_MAX_RECONNECTS,
+            reconnects: 0,
+            logger: logger,
+        }
+    }
+
+    /// The current connection
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.con
+    }
+
+    /// Set the keepalive interval, None disables keepalives
+    pub fn set_keepalive(&mut self, interval: Option<Duration>) {
+        self.keepalive = interval;
+        self.con.set_keepalive(interval);
+    }
+
+    /// Set the deadline of the connection, see `Connection::set_deadline`
+    pub fn set_deadline(&mut self, deadline: Duration) {
+        self.deadline = Some(deadline);
+        self.con.set_deadline(deadline);
+    }
+
+    /// Set the request rate limit of the connection, see `Connection::set_pacer`
+    pub fn set_pacer(&mut self, pacer: Option<Pacer>) {
+        self.pacer = pacer.clone();
+        self.con.set_pacer(pacer);
+    }
+
+    /// Where malformed packets of all connections are quarantined
+    pub fn dead_letters(&self) -> &DeadLetters {
+        &self.dead_letters
+    }
+
+    /// Quarantine malformed packets to another log, see `Connection::set_dead_letters`
+    pub fn set_dead_letters(&mut self, dead_letters: DeadLetters) {
+        self.dead_letters = dead_letters.clone();
+        self.con.set_dead_letters(dead_letters);
+    }
+
+    /// Set the maximum amount of reconnect attempts for a single dropped connection
+    pub fn set_max_reconnects(&mut self, max_reconnects: u32) {
+        self.max_reconnects = max_reconnects;
+    }
+
+    /// Amount of times the connection has been restored
+    pub fn reconnects(&self) -> u32 {
+        self.reconnects
+    }
+
+    /// Send a keepalive when it is time for one
+    pub fn keepalive(&mut self) -> Result<()> {
+        match self.con.keepalive() {
+            Err(ref err) if is_connection_lost(err) => self.reconnect(err),
+            result => result,
+        }
+    }
+
+    /// Send gge packet, reconnects when the connection dropped
+    pub fn send_packet(&mut self, packet: ClientPacket) -> Result<()> {
+        match self.con.send_packet(packet.clone()) {
+            Err(ref err) if is_connection_lost(err) => {
+                // The packet isn't outstanding yet, so it isn't resend by `reconnect`
+                self.reconnect(err)?;
+                self.send_packet(packet)
+            }
+            result => result,
+        }
+    }
+
+    /// Read the next answer, see `Connection::next_response`
+    ///
+    /// Reconnects when the connection dropped and resends the unanswered requests.
+    pub fn next_response(&mut self) -> Result<Option<ServerPacket>> {
+        loop {
+            match self.con.next_response() {
+                Err(ref err) if is_connection_lost(err) => self.reconnect(err)?,
+                result => return result,
+            }
+        }
+    }
+
+    /// Replace the connection and resend the unanswered requests
+    fn reconnect(&mut self, cause: &Error) -> Result<()> {
+        warn!(self.logger, "connection lost"; "cause" => cause.to_string());
+        let server = match self.server {
+            Some(server) => server,
+            None => return Err(ErrorKind::Disconnected.into()),
+        };
+        let pending = self.con.outstanding().requests();
+
+        let mut attempt = 0;
+        let con = loop {
+            attempt += 1;
+            thread::sleep(Duration::new(u64::from(attempt), 0));
+            info!(self.logger, "reconnecting"; "attempt" => attempt);
+            match Connection::recording(
+                server,
+                self.config.clone(),
+                &self.username,
+                &self.password,
+                self.recorder.clone(),
+                self.logger.clone(),
+            ) {
+                Ok(con) => break con,
+                Err(ref err) if attempt < self.max_reconnects && is_connection_lost(err) => {
+                    warn!(self.logger, "reconnect failed";
+                        "attempt" => attempt,
+                        "error" => err.to_string());
+                }
+                Err(err) => return Err(err),
+            }
+        };
+
+        self.con = con;
+        self.con.set_keepalive(self.keepalive);
+        if let Some(deadline) = self.deadline {
+            self.con.set_deadline(deadline);
+        }
+        self.con.set_pacer(self.pacer.clone());
+        self.con.set_dead_letters(self.dead_letters.clone());
+        self.reconnects += 1;
+        info!(self.logger, "reconnected"; "resending" => pending.len());
+
+        for packet in pending {
+            self.con.send_packet(packet)?;
+        }
+        Ok(())
+    }
+}
+
+impl PacketSender for Session {
+    fn send_packet(&mut self, packet: ClientPacket) -> Result<()> {
+        Session::send_packet(self, packet)
+    }
+}
+
+/// Did the error happen because the connection dropped?
+///
+/// The stream errors are classified where they happen: `SmartFoxClient` and `Connection` return
+/// `ErrorKind::ConnectionLost` for failed reads, writes and connects and `ErrorKind::Timeout`
+/// for timeouts.
+pub fn is_connection_lost(err: &Error) -> bool {
+    match *err.kind() {
+        ErrorKind::Disconnected | ErrorKind::ConnectionLost(_) => true,
+        ErrorKind::IoError(ref err) => !is_io_timeout(err),
+        _ => false,
+    }
+}
+
+fn is_io_timeout(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use error::ResultExt;
+
+    #[test]
+    fn detect_connection_lost() {
+        assert!(is_connection_lost(&ErrorKind::Disconnected.into()));
+        assert!(!is_connection_lost(&ErrorKind::Timeout.into()));
+        assert!(!is_connection_lost(&ErrorKind::LoginFailed(10).into()));
+
+        let write_failed = Error::with_chain(
+            io::Error::new(io::ErrorKind::BrokenPipe, "broken"),
+            ErrorKind::ConnectionLost("Cant write to server stream".into()),
+        );
+        assert!(is_connection_lost(&write_failed));
+        assert!(is_connection_lost(
+            &io::Error::new(io::ErrorKind::ConnectionReset, "reset").into(),
+        ));
+
+        let read_timeout: Result<()> = Err(io::Error::new(io::ErrorKind::WouldBlock, "timeout"))
+            .chain_err(|| "Couldnt read from stream");
+        assert!(!is_connection_lost(&read_timeout.unwrap_err()));
+    }
+}
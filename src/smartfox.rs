@@ -1,11 +1,22 @@
 use std::str;
 use std::fmt;
+use std::borrow::Cow;
+use std::mem;
 use std::io::prelude::*;
+use std::io::{self, BufReader, Cursor};
 use std::net::TcpStream;
+use std::time::Duration;
+use std::collections::VecDeque;
 
+use regex::Regex;
 use slog::*;
 
-use error::{Result, ResultExt};
+use error::{Error, ErrorKind, Result, ResultExt};
+use capture::{Direction, Recorder};
+use dead_letter::DeadLetters;
+
+/// SmartFoxServer client version send during the version check
+pub const SMARTFOX_VERSION: u32 = 166;
 
 pub struct SmartFoxPacket {
     pub data: String,
@@ -26,9 +37,194 @@ where
     SmartFoxPacket { data: data.into() }
 }
 
+/// SmartFoxServer system message
+///
+/// ```xml
+/// <msg t='sys'><body action='<#action#>' r='<#room#>'><#content#></body></msg>
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SysMessage {
+    /// Action, for example "apiOK" or "logKO"
+    pub action: String,
+    /// Room id
+    pub room: String,
+    /// Xml inside the body tag
+    pub content: String,
+}
+
+impl SysMessage {
+    /// Parse a system message. Returns None for other kinds of packets.
+    pub fn parse(data: &str) -> Option<Self> {
+        lazy_static!{
+            static ref SYS_MSG: Regex = Regex::new(
+                r"^(?s)<msg t='sys'><body action='([^']*)' r='([^']*)'\s*(?:/>|>(.*)</body>)</msg>$"
+            ).unwrap();
+        }
+        SYS_MSG.captures(data.trim()).map(|captures| {
+            SysMessage {
+                action: captures[1].to_string(),
+                room: captures[2].to_string(),
+                content: captures.get(3).map(|c| c.as_str()).unwrap_or("").to_string(),
+            }
+        })
+    }
+
+    /// The value of the `e` attribute, used by logKO for the reason of the rejection
+    pub fn error(&self) -> Option<String> {
+        lazy_static!{
+            static ref ERROR_ATTR: Regex = Regex::new(r"\se='([^']*)'").unwrap();
+        }
+        ERROR_ATTR.captures(&self.content).map(|captures| captures[1].to_string())
+    }
+}
+
+/// State of the SmartFoxServer handshake
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum HandshakeState {
+    /// Waiting for apiOK or apiKO
+    VersionCheck,
+    /// Waiting for logOK or logKO
+    Login,
+    /// Logged in
+    Done,
+}
+
+/// What to do after receiving a packet during the handshake
+#[derive(Debug)]
+pub enum HandshakeStep {
+    /// Send the packet and wait for the next answer
+    Send(SmartFoxPacket),
+    /// Wait for the next answer
+    Wait,
+    /// Logged in. Contains the received packet when it was a game packet instead of logOK.
+    Done(Option<SmartFoxPacket>),
+}
+
+/// SmartFoxServer version check and login, independent of the kind of connection
+pub struct Handshake {
+    state: HandshakeState,
+    login: SmartFoxPacket,
+    logger: Logger,
+}
+
+impl Handshake {
+    /// Create a new handshake for the given login
+    pub fn new(room: &str, username: &str, password: &str, logger: Logger) -> Self {
+        let login_header = format!(
+            "<msg t='sys'><body action='login' r='0'><login z='{}'><nick><![CDATA[{}]]></nick><pword><![CDATA[{}]]></pword></login></body></msg>",
+            room,
+            username,
+            password
+        );
+        Handshake {
+            state: HandshakeState::VersionCheck,
+            login: SmartFoxPacket(login_header),
+            logger: logger,
+        }
+    }
+
+    /// The first packet to send
+    pub fn start(&self) -> SmartFoxPacket {
+        SmartFoxPacket(format!(
+            "<msg t='sys'><body action='verChk' r='0'><ver v='{}' /></body></msg>",
+            SMARTFOX_VERSION
+        ))
+    }
+
+    /// Nothing arrived in time
+    ///
+    /// Like before the answers were parsed, a server that stays silent after the login is
+    /// assumed to have accepted it. When the version check isn't answered `ErrorKind::Timeout`
+    /// is returned.
+    pub fn timed_out(&mut self) -> Result<()> {
+        if self.state == HandshakeState::Login {
+            warn!(self.logger, "no answer to the smartfox login, continuing");
+            self.state = HandshakeState::Done;
+            Ok(())
+        } else {
+            Err(ErrorKind::Timeout.into())
+        }
+    }
+
+    /// Process a received packet
+    ///
+    /// # Errors
+    ///
+    /// * `ErrorKind::VersionMismatch` when the server answers apiKO
+    /// * `ErrorKind::LoginRejected` when the server answers logKO
+    /// * `ErrorKind::HandshakeFailed` for unexpected packets
+    pub fn receive(&mut self, packet: SmartFoxPacket) -> Result<HandshakeStep> {
+        let msg = match SysMessage::parse(&packet.data) {
+            Some(msg) => msg,
+            None if self.state == HandshakeState::Login => {
+                // Login handled by a server extension, it answers with game packets
+                self.state = HandshakeState::Done;
+                return Ok(HandshakeStep::Done(Some(packet)));
+            }
+            None => {
+                return Err(
+                    ErrorKind::HandshakeFailed(format!("unexpected packet {}", packet.data))
+                        .into(),
+                )
+            }
+        };
+
+        match (self.state, &*msg.action) {
+            (HandshakeState::VersionCheck, "apiOK") => {
+                self.state = HandshakeState::Login;
+                Ok(HandshakeStep::Send(SmartFoxPacket(self.login.data.clone())))
+            }
+            (HandshakeState::VersionCheck, "apiKO") => {
+                Err(ErrorKind::VersionMismatch(SMARTFOX_VERSION).into())
+            }
+            (HandshakeState::Login, "logOK") => {
+                self.state = HandshakeState::Done;
+                debug!(self.logger, "smartfox handshake done");
+                Ok(HandshakeStep::Done(None))
+            }
+            (HandshakeState::Login, "logKO") => {
+                Err(ErrorKind::LoginRejected(msg.error().unwrap_or_default()).into())
+            }
+            (state, _) => {
+                debug!(self.logger, "ignoring system message during handshake";
+                    "state" => format!("{:?}", state),
+                    "action" => msg.action.clone());
+                Ok(HandshakeStep::Wait)
+            }
+        }
+    }
+}
+
+/// Byte stream to a SmartFoxServer
+pub trait Transport: Read + Write + Send {
+    /// Set the time a read waits for data, see `TcpStream::set_read_timeout`
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Create a second handle to the same stream
+    fn try_clone(&self) -> io::Result<Box<Transport>>;
+}
+
+impl Transport for TcpStream {
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn try_clone(&self) -> io::Result<Box<Transport>> {
+        TcpStream::try_clone(self).map(|stream| Box::new(stream) as Box<Transport>)
+    }
+}
+
 /// Goodgame empire connection
 pub struct SmartFoxClient {
-    pub stream: TcpStream,
+    pub stream: Box<Transport>,
+    /// Received bytes not yet split into packets
+    buffer: Vec<u8>,
+    /// Received packets not yet returned
+    pending: VecDeque<SmartFoxPacket>,
+    /// Capture all send and received packets are written to
+    recorder: Option<Recorder>,
+    /// Quarantine for frames that aren't valid packets
+    dead_letters: DeadLetters,
     logger: Logger,
 }
 
@@ -52,96 +248,382 @@ impl SmartFoxClient {
     ///               <pword><![CDATA[<#password#>]]></pword>
     ///           </login>
     ///       </body></msg>
+    /// recv: <msg t='sys'><body action='logOK' r='0'>...</body></msg>
     /// ```
+    ///
+    /// # Errors
+    ///
+    /// * `ErrorKind::VersionMismatch` when the server answers apiKO
+    /// * `ErrorKind::LoginRejected` when the server answers logKO
+    /// * `ErrorKind::Timeout` when the server doesn't answer the version check in time. When
+    ///   only the login isn't answered the connection is used anyway.
     pub fn new(
         stream: TcpStream,
         room: &str,
         username: &str,
         password: &str,
         logger: Logger,
+    ) -> Result<Self> {
+        SmartFoxClient::with_transport(Box::new(stream), room, username, password, None, logger)
+    }
+
+    /// Create a new connection over any transport, see `new`
+    ///
+    /// When a recorder is given all packets, including the handshake, are written to it.
+    pub fn with_transport(
+        stream: Box<Transport>,
+        room: &str,
+        username: &str,
+        password: &str,
+        recorder: Option<Recorder>,
+        logger: Logger,
     ) -> Result<Self> {
         stream
-            .set_read_timeout(Some(::std::time::Duration::new(2, 0)))
+            .set_read_timeout(Some(Duration::new(2, 0)))
             .chain_err(|| "Couldnt set stream timeout")?;
 
         let mut con = SmartFoxClient {
             stream: stream,
-            logger: logger.clone(),
+            buffer: Vec::new(),
+            pending: VecDeque::new(),
+            recorder: recorder,
+            dead_letters: DeadLetters::new(),
+            logger: logger,
         };
 
-        let ver_chk_msg = "<msg t='sys'><body action='verChk' r='0'><ver v='166' /></body></msg>";
-        let _ = con.send_packet(SmartFoxPacket(ver_chk_msg.to_string()));
-        let header = con.recv().chain_err(|| "Couldn't connect to server")?;
+        con.handshake(room, username, password)?;
 
-        if header != "<msg t='sys'><body action='apiOK' r='0'></body></msg>" {
-            panic!("Invalid server version: {}", header);
-        }
+        Ok(con)
+    }
 
-        let login_header = format!(
-            "<msg t='sys'><body action='login' r='0'><login z='{}'><nick><![CDATA[{}]]></nick><pword><![CDATA[{}]]></pword></login></body></msg>",
-            room,
-            username,
-            password
-        );
+    fn handshake(&mut self, room: &str, username: &str, password: &str) -> Result<()> {
+        let mut handshake = Handshake::new(room, username, password, self.logger.clone());
+        self.send_packet(handshake.start())?;
+
+        loop {
+            let packet = match self.recv_packet() {
+                Ok(packet) => packet,
+                Err(Error(ErrorKind::Timeout, _)) => return handshake.timed_out(),
+                Err(err) => return Err(err),
+            };
+            match handshake.receive(packet)? {
+                HandshakeStep::Send(packet) => self.send_packet(packet)?,
+                HandshakeStep::Wait => {}
+                HandshakeStep::Done(packet) => {
+                    self.pending.extend(packet);
+                    return Ok(());
+                }
+            }
+        }
+    }
 
-        con.send_packet(SmartFoxPacket(login_header)).chain_err(
-            || "Couldn't connect to server",
-        )?;
-        con.read_packets(logger).chain_err(
-            || "Couldn't connect to server",
-        )?;
+    /// Where malformed frames are quarantined
+    pub fn dead_letters(&self) -> &DeadLetters {
+        &self.dead_letters
+    }
 
-        Ok(con)
+    /// Quarantine malformed frames to another log
+    pub fn set_dead_letters(&mut self, dead_letters: DeadLetters) {
+        self.dead_letters = dead_letters;
     }
 
     // raw connection
-    fn recv(&mut self) -> Result<String> {
-        let mut data = [0; 8192];
 
-        self.stream.read(&mut data).chain_err(
-            || "Couldnt read from stream",
-        )?;
+    /// Read a single zero terminated packet
+    ///
+    /// Data that arrives in multiple reads is buffered until the packet is complete.
+    /// Frames that aren't utf8 are quarantined to the dead letters and skipped.
+    pub fn recv_packet(&mut self) -> Result<SmartFoxPacket> {
+        if let Some(packet) = self.pending.pop_front() {
+            return Ok(packet);
+        }
 
-        let data = str::from_utf8(&data)
-            .chain_err(|| "Malformed utf8 data provided by the server")?
-            .trim_matches('\0')
-            .to_string();
+        loop {
+            if let Some(end) = self.buffer.iter().position(|&byte| byte == 0) {
+                let mut data = self.buffer.drain(..end + 1).collect::<Vec<u8>>();
+                data.pop(); // zero terminator
+                if let Some(ref recorder) = self.recorder {
+                    recorder.record(Direction::Recv, &data)?;
+                }
+                let data = match decode_frame(data, &self.dead_letters, &self.logger) {
+                    Ok(data) => data,
+                    Err(_) => continue,
+                };
+                trace!(self.logger, "   smartfox recv"; "data" => data.clone());
+                return Ok(SmartFoxPacket(data));
+            }
 
-        trace!(self.logger.clone(), "   smartfox recv"; "data" => data.clone());
+            let mut data = [0; 8192];
+            let read = match self.stream.read(&mut data) {
+                Ok(0) => return Err(ErrorKind::Disconnected.into()),
+                Ok(read) => read,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref err) if is_timeout(err) => return Err(ErrorKind::Timeout.into()),
+                // Only returned by `capture::Replay`
+                Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    return Err(ErrorKind::CaptureEnded.into())
+                }
+                Err(err) => {
+                    return Err(Error::with_chain(
+                        err,
+                        ErrorKind::ConnectionLost("Couldnt read from stream".into()),
+                    ))
+                }
+            };
+            self.buffer.extend_from_slice(&data[..read]);
+        }
+    }
 
-        Ok(data)
+    /// Return packets to the connection, they are returned again by the next reads
+    pub fn unread(&mut self, packets: Vec<SmartFoxPacket>) {
+        for packet in packets.into_iter().rev() {
+            self.pending.push_front(packet);
+        }
     }
 
     // clean connection
 
     /// Send a zero terminated packet
+    ///
+    /// The game password of lli packets is blanked out in the capture.
     pub fn send_packet(&mut self, packet: SmartFoxPacket) -> Result<()> {
+        if let Some(ref recorder) = self.recorder {
+            recorder.record(Direction::Send, redact_password(&packet.data).as_bytes())?;
+        }
         let data = packet.data + "\0";
-        self.stream.write(data.as_bytes()).chain_err(
-            || "Cant write to server stream",
-        )?;
-        Ok(())
+        self.stream.write_all(data.as_bytes()).map_err(|err| {
+            Error::with_chain(err, ErrorKind::ConnectionLost("Cant write to server stream".into()))
+        })
     }
 
     /// Read zero terminated packets
-    pub fn read_packets(&mut self, logger: Logger) -> Result<Box<Iterator<Item = SmartFoxPacket>>> {
+    ///
+    /// Read errors and frames that aren't utf8 are returned as errors, the iterator continues
+    /// after them. Malformed frames are quarantined to the dead letters.
+    pub fn read_packets(
+        &mut self,
+        logger: Logger,
+    ) -> Result<Box<Iterator<Item = Result<SmartFoxPacket>>>> {
         static SPLIT: &'static [u8] = &[0x00];
-        let buf_reader = Box::new(::std::io::BufReader::new(
+        let pending = mem::replace(&mut self.pending, VecDeque::new());
+        let buffered = mem::replace(&mut self.buffer, Vec::new());
+        let reader = Cursor::new(buffered).chain(BufReader::new(
             self.stream.try_clone().chain_err(|| "Couldnt clone stream")?,
         ));
-        let splitter = ::byte_stream_splitter::ByteStreamSplitter::new(buf_reader, SPLIT);
+        let splitter = ::byte_stream_splitter::ByteStreamSplitter::new(reader, SPLIT);
+        let recorder = self.recorder.clone();
+        let dead_letters = self.dead_letters.clone();
 
         let data = splitter
-            .map(|splited| {
-                String::from_utf8(splited.unwrap()).expect(
-                    "Malformed utf8 data provided by the server",
-                )
-            })
-            .map(move |data| {
-                trace!(logger.clone(), "Received data"; "data" => data.clone());
-                data
+            .map(move |splited| {
+                let splited = splited.chain_err(|| "Couldnt read from stream")?;
+                if let Some(ref recorder) = recorder {
+                    if let Err(err) = recorder.record(Direction::Recv, &splited) {
+                        error!(logger, "Couldnt record packet"; "error" => err.to_string());
+                    }
+                }
+                let data = decode_frame(splited, &dead_letters, &logger)?;
+                trace!(logger, "Received data"; "data" => data.clone());
+                Ok(SmartFoxPacket(data))
             });
 
-        Ok(Box::new(data.map(SmartFoxPacket)))
+        Ok(Box::new(pending.into_iter().map(Ok).chain(data)))
+    }
+}
+
+/// Replace the value of the `PW` field of a lli packet, the password is never logged
+fn redact_password(data: &str) -> Cow<str> {
+    lazy_static!{
+        static ref PASSWORD: Regex = Regex::new(r#""PW":"(?:[^"\\]|\\.)*""#).unwrap();
+    }
+    PASSWORD.replace_all(data, r#""PW":"""#)
+}
+
+/// Decode a received frame, quarantines it when it isn't utf8
+pub fn decode_frame(data: Vec<u8>, dead_letters: &DeadLetters, logger: &Logger) -> Result<String> {
+    String::from_utf8(data).map_err(|err| {
+        warn!(logger, "quarantined frame"; "reason" => "invalid utf8");
+        if let Err(err) = dead_letters.push("invalid utf8", err.as_bytes()) {
+            error!(logger, "Couldnt quarantine frame"; "error" => err.to_string());
+        }
+        ErrorKind::InvalidPacket("Malformed utf8 data provided by the server".into()).into()
+    })
+}
+
+/// Did a read fail because the read timeout expired?
+fn is_timeout(err: &io::Error) -> bool {
+    err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn parse_sys_message() {
+        assert_eq!(
+            SysMessage::parse("<msg t='sys'><body action='apiOK' r='0'></body></msg>"),
+            Some(SysMessage {
+                action: "apiOK".to_string(),
+                room: "0".to_string(),
+                content: "".to_string(),
+            })
+        );
+        let log_ko = SysMessage::parse(
+            "<msg t='sys'><body action='logKO' r='-1'><login e='Bad zone' /></body></msg>",
+        ).unwrap();
+        assert_eq!(log_ko.action, "logKO");
+        assert_eq!(log_ko.room, "-1");
+        assert_eq!(log_ko.error(), Some("Bad zone".to_string()));
+        assert_eq!(SysMessage::parse("%xt%lli%1%0%"), None);
+    }
+
+    fn read_frame(stream: &mut TcpStream) -> String {
+        let mut data = Vec::new();
+        for byte in stream.bytes() {
+            match byte.unwrap() {
+                0 => break,
+                byte => data.push(byte),
+            }
+        }
+        String::from_utf8(data).unwrap()
+    }
+
+    /// Connect to a server answering the version check and the login with the given data
+    fn handshake_with(
+        ver_chk: &'static [&'static str],
+        login: &'static str,
+    ) -> Result<SmartFoxClient> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert!(read_frame(&mut stream).contains("verChk"));
+            for part in ver_chk {
+                stream.write_all(part.as_bytes()).unwrap();
+                stream.flush().unwrap();
+                thread::sleep(::std::time::Duration::from_millis(10));
+            }
+            if !login.is_empty() {
+                assert!(read_frame(&mut stream).contains("<login z='EmpireEx_11'>"));
+                stream.write_all(login.as_bytes()).unwrap();
+            }
+            stream
+        });
+
+        let client = SmartFoxClient::new(
+            TcpStream::connect(addr).unwrap(),
+            "EmpireEx_11",
+            "",
+            "1455712286016%nl%",
+            Logger::root(Discard, o!()),
+        );
+        server.join().unwrap();
+        client
+    }
+
+    #[test]
+    fn handshake() {
+        let mut client = handshake_with(
+            &["<msg t='sys'><body action='api", "OK' r='0'></body></msg>\0"],
+            concat!(
+                "<msg t='sys'><body action='logOK' r='0'><login n='' id='1' mod='0'/></body></msg>\0",
+                "%xt%nfo%1%0%{}%\0"
+            ),
+        ).unwrap();
+        assert_eq!(client.recv_packet().unwrap().data, "%xt%nfo%1%0%{}%");
+    }
+
+    #[test]
+    fn password_not_recorded() {
+        use std::env;
+        use std::fs;
+        use capture::{CaptureReader, Replay};
+        use connection::LOCAL_CONFIG;
+        use packet::ClientPacket;
+
+        let path = env::temp_dir().join(format!("gge-login-{}.cap", ::std::process::id()));
+        let replay = Replay::from_frames(vec![]);
+        let mut client = SmartFoxClient {
+            stream: Box::new(replay),
+            buffer: Vec::new(),
+            pending: VecDeque::new(),
+            recorder: Some(Recorder::create(&path).unwrap()),
+            dead_letters: DeadLetters::new(),
+            logger: Logger::root(Discard, o!()),
+        };
+        let lli = ClientPacket::Lli {
+            username: "tester".to_string(),
+            password: "s3cr\"et".to_string(),
+        };
+        client
+            .send_packet(SmartFoxPacket(lli.to_raw_data(&LOCAL_CONFIG)))
+            .unwrap();
+
+        let frames = CaptureReader::new(fs::File::open(&path).unwrap())
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        let data = String::from_utf8(frames[0].data.clone()).unwrap();
+        assert!(data.contains(r#""PW":"","NOM":"tester""#), "{}", data);
+        assert!(!data.contains("s3cr"));
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let err = handshake_with(
+            &["<msg t='sys'><body action='apiKO' r='0'></body></msg>\0"],
+            "",
+        ).err()
+            .unwrap();
+        match *err.kind() {
+            ErrorKind::VersionMismatch(SMARTFOX_VERSION) => {}
+            ref kind => panic!("{:?}", kind),
+        }
+    }
+
+    #[test]
+    fn login_not_answered() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            assert!(read_frame(&mut stream).contains("verChk"));
+            stream
+                .write_all(b"<msg t='sys'><body action='apiOK' r='0'></body></msg>\0")
+                .unwrap();
+            // read the login so closing the socket doesn't reset the connection
+            assert!(read_frame(&mut stream).contains("<login z='EmpireEx_11'>"));
+            stream
+        });
+
+        let mut client = SmartFoxClient::new(
+            TcpStream::connect(addr).unwrap(),
+            "EmpireEx_11",
+            "",
+            "1455712286016%nl%",
+            Logger::root(Discard, o!()),
+        ).unwrap();
+        server.join().unwrap();
+        match client.recv_packet() {
+            Err(Error(ErrorKind::Disconnected, _)) => {}
+            packet => panic!("{:?}", packet),
+        }
+    }
+
+    #[test]
+    fn login_rejected() {
+        let err = handshake_with(
+            &["<msg t='sys'><body action='apiOK' r='0'></body></msg>\0"],
+            "<msg t='sys'><body action='logKO' r='0'><login e='Bad zone' /></body></msg>\0",
+        ).err()
+            .unwrap();
+        match *err.kind() {
+            ErrorKind::LoginRejected(ref reason) => assert_eq!(reason, "Bad zone"),
+            ref kind => panic!("{:?}", kind),
+        }
     }
 }
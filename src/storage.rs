@@ -0,0 +1,461 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use rusqlite::Connection;
+
+use error::{ErrorKind, Result, ResultExt};
+use data::{Alliance, Castle, CastleField, Change, DataMgr, User, World};
+
+/// Tables of the sqlite database, every import run is a separate snapshot
+const SCHEMA: &'static str = "
+    CREATE TABLE IF NOT EXISTS import_runs (
+        id INTEGER PRIMARY KEY,
+        server TEXT NOT NULL,
+        started INTEGER NOT NULL,
+        finished INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS castles (
+        run_id INTEGER NOT NULL REFERENCES import_runs(id),
+        id INTEGER NOT NULL,
+        owner_id INTEGER,
+        name TEXT,
+        x INTEGER,
+        y INTEGER,
+        world INTEGER,
+        PRIMARY KEY (run_id, id)
+    );
+    CREATE TABLE IF NOT EXISTS users (
+        run_id INTEGER NOT NULL REFERENCES import_runs(id),
+        id INTEGER NOT NULL,
+        username TEXT,
+        own_alliance INTEGER NOT NULL,
+        alliance_id INTEGER,
+        PRIMARY KEY (run_id, id)
+    );
+    CREATE TABLE IF NOT EXISTS alliances (
+        run_id INTEGER NOT NULL REFERENCES import_runs(id),
+        id INTEGER NOT NULL,
+        name TEXT,
+        PRIMARY KEY (run_id, id)
+    );
+    CREATE TABLE IF NOT EXISTS alliance_members (
+        run_id INTEGER NOT NULL REFERENCES import_runs(id),
+        alliance_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        rank INTEGER,
+        PRIMARY KEY (run_id, user_id)
+    );
+    CREATE TABLE IF NOT EXISTS castle_changes (
+        run_id INTEGER NOT NULL REFERENCES import_runs(id),
+        castle_id INTEGER NOT NULL,
+        time INTEGER NOT NULL,
+        field TEXT NOT NULL,
+        old TEXT NOT NULL,
+        new TEXT NOT NULL,
+        source TEXT
+    );
+    CREATE INDEX IF NOT EXISTS castles_by_id ON castles (id, run_id);
+";
+
+/// A single import
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportRun {
+    /// Name of the server the data was imported from
+    pub server: String,
+    /// Seconds since the unix epoch
+    pub started: u64,
+    /// Seconds since the unix epoch
+    pub finished: u64,
+}
+
+/// Somewhere the imported data can be flushed to, see `DataMgr::flush`
+pub trait Storage {
+    /// Store the data of an import
+    ///
+    /// Returns the id of the stored run when the storage keeps multiple runs.
+    fn save(&mut self, run: &ImportRun, data: &DataMgr) -> Result<Option<i64>>;
+}
+
+/// Json file which is overwritten by every import
+pub struct JsonFile {
+    path: PathBuf,
+}
+
+impl JsonFile {
+    /// Store to the given file
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        JsonFile { path: path.as_ref().to_path_buf() }
+    }
+
+    /// Read the data written by an earlier import
+    pub fn load(&self) -> Result<DataMgr> {
+        let f = File::open(&self.path).chain_err(|| "Cant open data file")?;
+        ::serde_json::from_reader(f).chain_err(|| "Cant parse data file")
+    }
+}
+
+impl Storage for JsonFile {
+    fn save(&mut self, _run: &ImportRun, data: &DataMgr) -> Result<Option<i64>> {
+        let mut f = File::create(&self.path).chain_err(|| "Cant open data file")?;
+        write!(
+            f,
+            "{}",
+            ::to_json(data).chain_err(|| "Cant serialize data")?
+        ).chain_err(|| "Cant write data to file")?;
+        Ok(None)
+    }
+}
+
+/// Sqlite database keeping a snapshot of every import
+pub struct SqliteStorage {
+    con: Connection,
+}
+
+impl SqliteStorage {
+    /// Open or create a database file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let con = Connection::open(path).chain_err(|| "Cant open database")?;
+        SqliteStorage::with_connection(con)
+    }
+
+    /// Create a database which only lives in memory
+    pub fn in_memory() -> Result<Self> {
+        SqliteStorage::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(con: Connection) -> Result<Self> {
+        con.execute_batch(SCHEMA).chain_err(|| "Cant create tables")?;
+        Ok(SqliteStorage { con: con })
+    }
+
+    /// The underlying connection, for queries that have no method
+    pub fn connection(&self) -> &Connection {
+        &self.con
+    }
+
+    /// All import runs with their id, oldest first
+    pub fn runs(&self) -> Result<Vec<(i64, ImportRun)>> {
+        let mut stmt = self.con.prepare(
+            "SELECT id, server, started, finished FROM import_runs ORDER BY id",
+        )?;
+        let rows = stmt.query_map(&[], |row| {
+            (
+                row.get::<_, i64>(0),
+                ImportRun {
+                    server: row.get(1),
+                    started: row.get::<_, i64>(2) as u64,
+                    finished: row.get::<_, i64>(3) as u64,
+                },
+            )
+        })?;
+        let mut runs = Vec::new();
+        for run in rows {
+            runs.push(run?);
+        }
+        Ok(runs)
+    }
+
+    /// The id of the newest import run
+    pub fn latest_run(&self) -> Result<Option<i64>> {
+        Ok(self.runs()?.last().map(|&(id, _)| id))
+    }
+
+    /// The data of an import run
+    pub fn load(&self, run_id: i64) -> Result<DataMgr> {
+        let exists = self.con.query_row(
+            "SELECT COUNT(*) FROM import_runs WHERE id = ?",
+            &[&run_id],
+            |row| row.get::<_, i64>(0),
+        )?;
+        if exists == 0 {
+            return Err(ErrorKind::UnknownRun(run_id).into());
+        }
+
+        let mut data = DataMgr::new();
+
+        let mut stmt = self.con.prepare(
+            "SELECT id, owner_id, name, x, y, world FROM castles WHERE run_id = ?",
+        )?;
+        let rows = stmt.query_map(&[&run_id], |row| {
+            Castle {
+                id: row.get::<_, i64>(0) as u64,
+                owner_id: row.get::<_, Option<i64>>(1).map(|id| id as u64),
+                name: row.get(2),
+                x: row.get::<_, Option<i64>>(3).map(|x| x as u64),
+                y: row.get::<_, Option<i64>>(4).map(|y| y as u64),
+                world: row.get::<_, Option<i64>>(5).and_then(
+                    |world| World::from_number(world as u64),
+                ),
+            }
+        })?;
+        for castle in rows {
+            let castle = castle?;
+            data.castles.insert(castle.id, castle);
+        }
+
+        let mut stmt = self.con.prepare(
+            "SELECT id, username, own_alliance, alliance_id FROM users WHERE run_id = ?",
+        )?;
+        let rows = stmt.query_map(&[&run_id], |row| {
+            User {
+                id: row.get::<_, i64>(0) as u64,
+                username: row.get(1),
+                own_alliance: row.get(2),
+                alliance_id: row.get::<_, Option<i64>>(3).map(|id| id as u64),
+            }
+        })?;
+        for user in rows {
+            let user = user?;
+            data.users.insert(user.id, user);
+        }
+
+        let mut stmt = self.con.prepare(
+            "SELECT id, name FROM alliances WHERE run_id = ?",
+        )?;
+        let rows = stmt.query_map(&[&run_id], |row| {
+            Alliance::new(row.get::<_, i64>(0) as u64, row.get(1))
+        })?;
+        for alliance in rows {
+            let alliance = alliance?;
+            data.alliances.insert(alliance.id, alliance);
+        }
+
+        let mut stmt = self.con.prepare(
+            "SELECT alliance_id, user_id, rank FROM alliance_members WHERE run_id = ?",
+        )?;
+        let rows = stmt.query_map(&[&run_id], |row| {
+            (
+                row.get::<_, i64>(0) as u64,
+                row.get::<_, i64>(1) as u64,
+                row.get::<_, Option<i64>>(2).map(|rank| rank as u64),
+            )
+        })?;
+        for member in rows {
+            let (alliance_id, user_id, rank) = member?;
+            data.add_alliance(alliance_id, None).members.insert(user_id, rank);
+        }
+
+        let mut stmt = self.con.prepare(
+            "SELECT castle_id, time, field, old, new, source FROM castle_changes
+             WHERE run_id = ? ORDER BY rowid",
+        )?;
+        let rows = stmt.query_map(&[&run_id], |row| {
+            let field = row.get::<_, String>(2);
+            (
+                row.get::<_, i64>(0) as u64,
+                row.get::<_, i64>(1) as u64,
+                parse_field(&field, &row.get::<_, String>(3)),
+                parse_field(&field, &row.get::<_, String>(4)),
+                row.get(5),
+            )
+        })?;
+        for change in rows {
+            if let (castle_id, time, Some(old), Some(new), source) = change? {
+                data.changes.push(Change {
+                    time: time,
+                    castle_id: castle_id,
+                    old: old,
+                    new: new,
+                    source: source,
+                });
+            }
+        }
+
+        Ok(data)
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn save(&mut self, run: &ImportRun, data: &DataMgr) -> Result<Option<i64>> {
+        let tx = self.con.transaction()?;
+        tx.execute(
+            "INSERT INTO import_runs (server, started, finished) VALUES (?, ?, ?)",
+            &[&run.server, &(run.started as i64), &(run.finished as i64)],
+        )?;
+        let run_id = tx.last_insert_rowid();
+
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO castles (run_id, id, owner_id, name, x, y, world)
+                 VALUES (?, ?, ?, ?, ?, ?, ?)",
+            )?;
+            for castle in data.castles.values() {
+                stmt.execute(&[
+                    &run_id,
+                    &(castle.id as i64),
+                    &castle.owner_id.map(|id| id as i64),
+                    &castle.name,
+                    &castle.x.map(|x| x as i64),
+                    &castle.y.map(|y| y as i64),
+                    &castle.world.map(|world| world as i64),
+                ])?;
+            }
+
+            let mut stmt = tx.prepare(
+                "INSERT INTO users (run_id, id, username, own_alliance, alliance_id)
+                 VALUES (?, ?, ?, ?, ?)",
+            )?;
+            for user in data.users.values() {
+                stmt.execute(&[
+                    &run_id,
+                    &(user.id as i64),
+                    &user.username,
+                    &user.own_alliance,
+                    &user.alliance_id.map(|id| id as i64),
+                ])?;
+            }
+
+            let mut alliance_stmt = tx.prepare(
+                "INSERT INTO alliances (run_id, id, name) VALUES (?, ?, ?)",
+            )?;
+            let mut member_stmt = tx.prepare(
+                "INSERT INTO alliance_members (run_id, alliance_id, user_id, rank)
+                 VALUES (?, ?, ?, ?)",
+            )?;
+            for alliance in data.alliances.values() {
+                alliance_stmt.execute(
+                    &[&run_id, &(alliance.id as i64), &alliance.name],
+                )?;
+                for (user_id, rank) in &alliance.members {
+                    member_stmt.execute(&[
+                        &run_id,
+                        &(alliance.id as i64),
+                        &(*user_id as i64),
+                        &rank.map(|rank| rank as i64),
+                    ])?;
+                }
+            }
+
+            let mut stmt = tx.prepare(
+                "INSERT INTO castle_changes (run_id, castle_id, time, field, old, new, source)
+                 VALUES (?, ?, ?, ?, ?, ?, ?)",
+            )?;
+            for change in &data.changes {
+                let (field, old) = field_parts(&change.old);
+                let (_, new) = field_parts(&change.new);
+                stmt.execute(&[
+                    &run_id,
+                    &(change.castle_id as i64),
+                    &(change.time as i64),
+                    &field,
+                    &old,
+                    &new,
+                    &change.source,
+                ])?;
+            }
+        }
+
+        tx.commit()?;
+        Ok(Some(run_id))
+    }
+}
+
+/// Column name and text of a changed field
+fn field_parts(field: &CastleField) -> (&'static str, String) {
+    match *field {
+        CastleField::Owner(id) => ("owner", id.to_string()),
+        CastleField::Name(ref name) => ("name", name.clone()),
+        CastleField::X(x) => ("x", x.to_string()),
+        CastleField::Y(y) => ("y", y.to_string()),
+        CastleField::World(world) => ("world", (world as u64).to_string()),
+    }
+}
+
+/// Inverse of `field_parts`
+fn parse_field(field: &str, value: &str) -> Option<CastleField> {
+    match field {
+        "owner" => value.parse().ok().map(CastleField::Owner),
+        "name" => Some(CastleField::Name(value.to_string())),
+        "x" => value.parse().ok().map(CastleField::X),
+        "y" => value.parse().ok().map(CastleField::Y),
+        "world" => {
+            value.parse().ok().and_then(World::from_number).map(
+                CastleField::World,
+            )
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> DataMgr {
+        let mut data = DataMgr::new();
+        data.add_owner_name(1, "tester", true);
+        data.add_alliance(7, Some("Knights".to_string()));
+        data.add_member(7, 1, Some(3));
+        data.add_castle(Castle {
+            id: 42,
+            owner_id: Some(1),
+            name: Some("Home".to_string()),
+            x: Some(10),
+            y: Some(20),
+            world: Some(World::Ice),
+        });
+        data.add_castle_from(
+            Castle {
+                id: 42,
+                owner_id: None,
+                name: Some("New home".to_string()),
+                x: None,
+                y: None,
+                world: None,
+            },
+            Some("gdi"),
+        );
+        data
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut storage = SqliteStorage::in_memory().unwrap();
+        let run = ImportRun {
+            server: "dutch".to_string(),
+            started: 100,
+            finished: 160,
+        };
+        let data = sample();
+        let first = data.flush(&mut storage, &run).unwrap().unwrap();
+        let second = data.flush(&mut storage, &run).unwrap().unwrap();
+        assert!(second > first);
+        assert_eq!(storage.runs().unwrap().len(), 2);
+        assert_eq!(storage.runs().unwrap()[0], (first, run));
+        assert_eq!(storage.latest_run().unwrap(), Some(second));
+
+        let loaded = storage.load(first).unwrap();
+        assert_eq!(loaded.castles, data.castles);
+        assert_eq!(loaded.users, data.users);
+        assert_eq!(loaded.alliances, data.alliances);
+        assert_eq!(loaded.changes, data.changes);
+
+        match storage.load(second + 1) {
+            Err(::error::Error(ErrorKind::UnknownRun(_), _)) => {}
+            result => panic!("{:?}", result.map(|data| data.castles)),
+        }
+    }
+
+    #[test]
+    fn query_with_sql() {
+        let mut storage = SqliteStorage::in_memory().unwrap();
+        let run = ImportRun {
+            server: "dutch".to_string(),
+            started: 100,
+            finished: 160,
+        };
+        sample().flush(&mut storage, &run).unwrap();
+        let name = storage
+            .connection()
+            .query_row(
+                "SELECT castles.name FROM castles
+                 JOIN users ON users.run_id = castles.run_id AND users.id = castles.owner_id
+                 WHERE users.username = 'tester'",
+                &[],
+                |row| row.get::<_, String>(0),
+            )
+            .unwrap();
+        assert_eq!(name, "New home");
+    }
+}
@@ -0,0 +1,493 @@
+use std::collections::{BTreeMap, HashSet};
+
+use rand::{Rng, SeedableRng, StdRng};
+use serde_json::{Value, from_value};
+
+use data::{Alliance, Castle, DataMgr, User, World};
+use map_scan::{Area, MAP_SIZE};
+use scenario::{Request, Response, Responder};
+
+/// Syllables player, alliance and castle names are made of
+const SYLLABLES: &'static [&'static str] = &[
+    "an", "bel", "cor", "dor", "el", "fen", "gar", "hal", "ir", "kas", "lor", "mar", "nor", "os",
+    "pel", "quin", "ros", "sar", "tor", "ul", "vin", "wes", "yr", "zan",
+];
+
+/// Kingdoms players can have castles in besides the green world
+const KINGDOMS: &'static [World] = &[World::Sand, World::Ice, World::Fire];
+
+/// Settings of a generated world
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorldGenConfig {
+    /// Seed of the random generator, the same seed generates the same world
+    pub seed: usize,
+    /// Amount of players
+    pub players: u64,
+    /// Amount of alliances
+    pub alliances: u64,
+    /// Maximum amount of castles a player has besides the main castle
+    pub extra_castles: u64,
+}
+
+impl Default for WorldGenConfig {
+    fn default() -> Self {
+        WorldGenConfig {
+            seed: 1,
+            players: 100,
+            alliances: 10,
+            extra_castles: 3,
+        }
+    }
+}
+
+/// Generated alliance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenAlliance {
+    /// Internal id
+    pub id: u64,
+    /// Alliance name
+    pub name: String,
+}
+
+/// Generated player
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenPlayer {
+    /// Internal id
+    pub id: u64,
+    /// Username
+    pub name: String,
+    /// Id of the alliance the player is member of
+    pub alliance_id: Option<u64>,
+}
+
+/// Generated castle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenCastle {
+    /// Internal id
+    pub id: u64,
+    /// Internal owner id
+    pub owner_id: u64,
+    /// Castle name
+    pub name: String,
+    /// X position
+    pub x: u64,
+    /// Y position
+    pub y: u64,
+    /// World
+    pub world: World,
+}
+
+/// Fake game world, which answers requests like the game server would
+///
+/// The first player is the logged in player. The gbd packet send after the login contains the
+/// castles of the members of its alliance, gdi packets the castles of a player and gaa packets
+/// the castles in the requested area.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeneratedWorld {
+    /// Alliances
+    pub alliances: Vec<GenAlliance>,
+    /// Players, the first one is the logged in player
+    pub players: Vec<GenPlayer>,
+    /// Castles
+    pub castles: Vec<GenCastle>,
+}
+
+impl GeneratedWorld {
+    /// Generate a world
+    pub fn generate(config: &WorldGenConfig) -> Self {
+        let mut rng: StdRng = SeedableRng::from_seed(&[config.seed][..]);
+
+        let alliances = (0..config.alliances)
+            .map(|i| {
+                GenAlliance {
+                    id: i + 1,
+                    name: format!("{} alliance", capitalize(&gen_name(&mut rng, 2))),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let players = (0..config.players)
+            .map(|i| {
+                // The logged in player always has an alliance when there are alliances
+                let without = i != 0 && rng.gen_weighted_bool(4);
+                let alliance_id = if alliances.is_empty() || without {
+                    None
+                } else {
+                    Some(rng.gen_range(1, config.alliances + 1))
+                };
+                GenPlayer {
+                    id: i + 1,
+                    name: format!("{}{}", capitalize(&gen_name(&mut rng, 3)), i + 1),
+                    alliance_id: alliance_id,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut used = HashSet::new();
+        let mut castles = Vec::new();
+        for player in &players {
+            let extra = rng.gen_range(0, config.extra_castles + 1);
+            for i in 0..extra + 1 {
+                let world = if i == 0 {
+                    World::Grass
+                } else {
+                    *rng.choose(KINGDOMS).unwrap()
+                };
+                let (x, y) = loop {
+                    let x = rng.gen_range(0, MAP_SIZE);
+                    let y = rng.gen_range(0, MAP_SIZE);
+                    if used.insert((world, x, y)) {
+                        break (x, y);
+                    }
+                };
+                let id = 1000 + castles.len() as u64;
+                castles.push(GenCastle {
+                    id: id,
+                    owner_id: player.id,
+                    name: capitalize(&gen_name(&mut rng, 2)),
+                    x: x,
+                    y: y,
+                    world: world,
+                });
+            }
+        }
+
+        GeneratedWorld {
+            alliances: alliances,
+            players: players,
+            castles: castles,
+        }
+    }
+
+    /// The logged in player
+    pub fn own_player(&self) -> Option<&GenPlayer> {
+        self.players.first()
+    }
+
+    /// Players of the alliance of the logged in player, only the logged in player when it has
+    /// no alliance
+    pub fn own_alliance_members(&self) -> Vec<&GenPlayer> {
+        let own = match self.own_player() {
+            Some(own) => own,
+            None => return Vec::new(),
+        };
+        self.players
+            .iter()
+            .filter(|player| {
+                player.id == own.id ||
+                    (own.alliance_id.is_some() && player.alliance_id == own.alliance_id)
+            })
+            .collect()
+    }
+
+    /// Castles of a player
+    pub fn castles_of(&self, player_id: u64) -> Vec<&GenCastle> {
+        self.castles
+            .iter()
+            .filter(|castle| castle.owner_id == player_id)
+            .collect()
+    }
+
+    /// Castles inside an area of a world
+    pub fn castles_in(&self, world: World, area: Area) -> Vec<&GenCastle> {
+        self.castles
+            .iter()
+            .filter(|castle| {
+                castle.world == world && area.x1 <= castle.x && castle.x <= area.x2 &&
+                    area.y1 <= castle.y && castle.y <= area.y2
+            })
+            .collect()
+    }
+
+    /// The data a complete import should find
+    pub fn ground_truth(&self) -> DataMgr {
+        let own_alliance = self.own_alliance_members()
+            .iter()
+            .map(|player| player.id)
+            .collect::<HashSet<_>>();
+        let mut data_mgr = DataMgr::new();
+        for player in &self.players {
+            data_mgr.users.insert(
+                player.id,
+                User {
+                    id: player.id,
+                    username: Some(player.name.clone()),
+                    own_alliance: own_alliance.contains(&player.id),
+                    alliance_id: player.alliance_id,
+                },
+            );
+        }
+        for alliance in &self.alliances {
+            let members = self.players
+                .iter()
+                .filter(|player| player.alliance_id == Some(alliance.id))
+                .map(|player| (player.id, None))
+                .collect();
+            data_mgr.alliances.insert(
+                alliance.id,
+                Alliance {
+                    id: alliance.id,
+                    name: Some(alliance.name.clone()),
+                    members: members,
+                },
+            );
+        }
+        for castle in &self.castles {
+            data_mgr.castles.insert(
+                castle.id,
+                Castle {
+                    id: castle.id,
+                    owner_id: Some(castle.owner_id),
+                    name: Some(castle.name.clone()),
+                    x: Some(castle.x),
+                    y: Some(castle.y),
+                    world: Some(castle.world),
+                },
+            );
+        }
+        data_mgr
+    }
+
+    fn player(&self, id: u64) -> Option<&GenPlayer> {
+        self.players.iter().find(|player| player.id == id)
+    }
+
+    fn alliance(&self, id: u64) -> Option<&GenAlliance> {
+        self.alliances.iter().find(|alliance| alliance.id == id)
+    }
+
+    /// gbd data: the logged in player and the castles of its alliance
+    pub fn gbd(&self) -> Value {
+        let own = self.own_player();
+        let alliance = own.and_then(|own| own.alliance_id).and_then(|id| self.alliance(id));
+        let members = self.own_alliance_members()
+            .into_iter()
+            .map(|player| {
+                let (ap, vp): (Vec<_>, Vec<_>) = self.castles_of(player.id)
+                    .into_iter()
+                    .partition(|castle| castle.world == World::Grass);
+                let to_json = |castles: Vec<&GenCastle>| {
+                    castles
+                        .into_iter()
+                        .map(|castle| {
+                            json!([castle.world as u64, castle.id, castle.x, castle.y, 1])
+                        })
+                        .collect::<Vec<_>>()
+                };
+                json!({
+                    "OID": player.id,
+                    "N": player.name,
+                    "AP": to_json(ap),
+                    "VP": to_json(vp),
+                })
+            })
+            .collect::<Vec<_>>();
+        json!({
+            "gpi": own.map(|own| {
+                json!({
+                    "UID": own.id,
+                    "N": own.name,
+                    "AID": own.alliance_id,
+                })
+            }),
+            "gcl": own.map(|own| self.gdi(own.id)["gcl"].clone()),
+            "ain": {
+                "A": {
+                    "AID": alliance.map(|alliance| alliance.id),
+                    "N": alliance.map(|alliance| alliance.name.clone()),
+                    "M": members,
+                },
+            },
+        })
+    }
+
+    /// gdi data: the castles of a player, grouped by world
+    pub fn gdi(&self, player_id: u64) -> Value {
+        let mut worlds = BTreeMap::new();
+        for castle in self.castles_of(player_id) {
+            worlds
+                .entry(castle.world as u64)
+                .or_insert_with(Vec::new)
+                .push(json!({"AI": castle_ai(castle)}));
+        }
+        let player = self.player(player_id);
+        json!({
+            "O": {
+                "OID": player_id,
+                "N": player.map(|player| player.name.clone()),
+                "AID": player.and_then(|player| player.alliance_id),
+            },
+            "gcl": {
+                "C": worlds
+                    .into_iter()
+                    .map(|(world, castles)| json!({"KID": world, "AI": castles}))
+                    .collect::<Vec<_>>(),
+            },
+        })
+    }
+
+    /// gaa data: the castles in an area and their owners
+    pub fn gaa(&self, world: World, area: Area) -> Value {
+        let castles = self.castles_in(world, area);
+        let mut owners = BTreeMap::new();
+        for castle in &castles {
+            owners
+                .entry(castle.owner_id)
+                .or_insert_with(Vec::new)
+                .push(json!([castle.world as u64, castle.id, castle.x, castle.y]));
+        }
+        json!({
+            "KID": world as u64,
+            "AX1": area.x1,
+            "AY1": area.y1,
+            "AX2": area.x2,
+            "AY2": area.y2,
+            "OI": owners
+                .into_iter()
+                .map(|(owner_id, castles)| {
+                    let player = self.player(owner_id);
+                    let alliance = player.and_then(|player| player.alliance_id).and_then(|id| {
+                        self.alliance(id)
+                    });
+                    // AID and AN are the assumed names, see `map::Gaa::parse_val`
+                    json!({
+                        "OID": owner_id,
+                        "N": player.map(|player| player.name.clone()),
+                        "AID": alliance.map(|alliance| alliance.id),
+                        "AN": alliance.map(|alliance| alliance.name.clone()),
+                        "AP": castles,
+                        "VP": [],
+                    })
+                })
+                .collect::<Vec<_>>(),
+            "AI": castles.into_iter().map(castle_ai).collect::<Vec<_>>(),
+        })
+    }
+}
+
+impl Responder for GeneratedWorld {
+    fn respond(&mut self, request: &Request) -> Response {
+        let packets = match &*request.command {
+            "lli" => vec!["%xt%lli%1%0%".to_string(), packet("gbd", &self.gbd())],
+            "gdi" => {
+                match request.data.get("PID").and_then(Value::as_u64) {
+                    Some(id) => vec![packet("gdi", &self.gdi(id))],
+                    None => Vec::new(),
+                }
+            }
+            "gaa" => {
+                #[derive(Deserialize)]
+                #[allow(non_snake_case)]
+                struct Gaa {
+                    KID: World,
+                    AX1: u64,
+                    AY1: u64,
+                    AX2: u64,
+                    AY2: u64,
+                }
+                match from_value::<Gaa>(request.data.clone()) {
+                    Ok(gaa) => {
+                        let area = Area {
+                            x1: gaa.AX1,
+                            y1: gaa.AY1,
+                            x2: gaa.AX2,
+                            y2: gaa.AY2,
+                        };
+                        vec![packet("gaa", &self.gaa(gaa.KID, area))]
+                    }
+                    Err(_) => Vec::new(),
+                }
+            }
+            _ => Vec::new(),
+        };
+        Response {
+            packets: packets,
+            ..Response::default()
+        }
+    }
+}
+
+/// Castle as found in gdi and gaa AI arrays: `[type, x, y, id, owner id, ..., name]`
+fn castle_ai(castle: &GenCastle) -> Value {
+    json!([1, castle.x, castle.y, castle.id, castle.owner_id, 0, 0, 0, 0, castle.name])
+}
+
+fn packet(command: &str, data: &Value) -> String {
+    format!("%xt%{}%1%0%{}%", command, data)
+}
+
+fn gen_name<R: Rng>(rng: &mut R, syllables: usize) -> String {
+    (0..syllables)
+        .map(|_| *rng.choose(SYLLABLES).unwrap())
+        .collect()
+}
+
+fn capitalize(name: &str) -> String {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_extractors::map::Gaa;
+
+    #[test]
+    fn deterministic() {
+        let config = WorldGenConfig::default();
+        let world = GeneratedWorld::generate(&config);
+        assert_eq!(world, GeneratedWorld::generate(&config));
+        assert_eq!(world.players.len(), 100);
+        assert!(world.castles.len() >= 100);
+        assert!(world.own_player().unwrap().alliance_id.is_some());
+
+        let other = GeneratedWorld::generate(&WorldGenConfig { seed: 2, ..config });
+        assert!(world != other);
+    }
+
+    #[test]
+    fn gaa_matches_ground_truth() {
+        let mut world = GeneratedWorld::generate(&WorldGenConfig::default());
+        let castle = world.castles[0].clone();
+        let area = Area {
+            x1: castle.x,
+            y1: castle.y,
+            x2: castle.x + 12,
+            y2: castle.y + 12,
+        };
+        let request = Request {
+            command: "gaa".to_string(),
+            data: json!({
+                "KID": 0,
+                "AX1": area.x1,
+                "AY1": area.y1,
+                "AX2": area.x2,
+                "AY2": area.y2,
+            }),
+        };
+        let response = world.respond(&request);
+        assert_eq!(response.packets.len(), 1);
+
+        let data = response.packets[0].splitn(6, '%').nth(5).unwrap().to_string();
+        let gaa = Gaa::parse(data).unwrap();
+        assert_eq!(gaa.area, Some(area));
+        let truth = world.ground_truth();
+        assert!(gaa.castles.iter().any(|found| found.id == castle.id));
+        for user in &gaa.users {
+            assert_eq!(user.alliance_id, truth.users[&user.id].alliance_id);
+        }
+        for alliance in &gaa.alliances {
+            assert_eq!(alliance.name, truth.alliances[&alliance.id].name);
+        }
+        for found in gaa.castles {
+            let expected = &truth.castles[&found.id];
+            assert_eq!(
+                (found.owner_id, found.x, found.y),
+                (expected.owner_id, expected.x, expected.y)
+            );
+        }
+    }
+}
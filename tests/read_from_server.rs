@@ -1,67 +1,352 @@
 #[macro_use]
 extern crate slog;
-extern crate slog_scope;
-extern crate slog_term;
+extern crate serde_json;
+extern crate futures;
+extern crate tokio_core;
 
 extern crate gge;
-use std::io::Write;
 
-use gge::to_json;
-use gge::packet::{ServerPacket, ClientPacket};
-use gge::connection::{Connection, LOCAL_SERVER, DUTCH_SERVER};
-use gge::data::DATAMGR;
+use std::time::Duration;
+
+use futures::{Future, Sink, Stream};
+use tokio_core::reactor::Core;
+
+use gge::error::ErrorKind;
+use gge::packet::{ServerPacket, ClientPacket, GaaRequest};
+use gge::connection::{Connection, LOCAL_CONFIG};
+use gge::async_connection::AsyncConnection;
+use gge::session::Session;
+use gge::data::{DataMgr, World};
+use gge::data_extractors::gbd::Gbd;
+use gge::data_extractors::map;
+use gge::dispatcher::Dispatcher;
+use gge::map_scan::{Area, MapScan, TILE_SIZE};
+use gge::dummy_server::DummyServer;
+use gge::scenario::Scenario;
+use gge::world_gen::{GeneratedWorld, WorldGenConfig};
+
+fn logger() -> slog::Logger {
+    slog::Logger::root(slog::Discard, o!())
+}
+
+fn fixture() -> Scenario {
+    Scenario::load(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/scenario.json"
+    )).unwrap()
+}
+
+fn connect(server: &DummyServer) -> Connection {
+    Connection::new(
+        server.addr(),
+        LOCAL_CONFIG.clone(),
+        "tester",
+        "secret",
+        logger(),
+    ).unwrap()
+}
+
+/// Login and read the gbd packet
+fn login(server: &DummyServer) -> (Connection, Gbd) {
+    let mut con = connect(server);
+    let gbd = match con.next_response().unwrap() {
+        Some(ServerPacket::Gbd(gbd)) => gbd.data,
+        packet => panic!("expected gbd, got {:?}", packet),
+    };
+    assert!(con.next_response().unwrap().is_none());
+    (con, gbd)
+}
+
+#[test]
+fn login_and_gbd() {
+    let server = DummyServer::start(fixture(), logger()).unwrap();
+    let (_con, gbd) = login(&server);
+
+    assert_eq!(gbd.ain.len(), 2);
+    assert_eq!(gbd.ain[0].oid, 1);
+    assert_eq!(gbd.ain[0].n, "tester");
+    assert_eq!(gbd.ain[0].ap[0].id, 100);
+    assert_eq!(gbd.ain[0].ap[0].x, Some(10));
+    assert_eq!(gbd.ain[0].vp[0].world, Some(World::Ice));
+    assert_eq!(gbd.ain[1].n, "friend");
+    assert_eq!(gbd.gpi.unwrap().name, Some("tester".to_string()));
+}
 
 #[test]
-fn read_from_server() {
-    let logger = slog::Logger::root(slog::Discard, o!());
+fn own_account_import() {
+    let server = DummyServer::start(fixture(), logger()).unwrap();
+    let mut con = connect(&server);
+    let packet = con.next_response().unwrap().expect("expected gbd");
 
-    slog_scope::set_global_logger(logger.clone());
+    let mut data_mgr = DataMgr::new();
+    let mut requests = Vec::new();
+    assert!(
+        Dispatcher::with_extractors()
+            .dispatch(&packet, &mut data_mgr, &mut requests)
+            .unwrap()
+    );
+    assert!(requests.is_empty());
 
-    let un = std::env::var("GGE_USERNAME").unwrap();
-    let pw = std::env::var("GGE_PASSWORD").unwrap();
+    let castle = &data_mgr.castles[&100];
+    assert_eq!(castle.owner_id, Some(1));
+    assert_eq!(castle.name, Some("Tester castle".to_string()));
+    assert_eq!((castle.x, castle.y), (Some(10), Some(20)));
+    assert_eq!(data_mgr.castles[&101].owner_id, Some(1));
+    assert_eq!(data_mgr.users[&1].username, Some("tester".to_string()));
+}
+
+#[test]
+fn login_failed() {
+    let scenario = serde_json::from_str(
+        r#"{"rules": [{"command": "lli", "packets": ["%xt%lli%1%10%"]}]}"#,
+    ).unwrap();
+    let server = DummyServer::start::<Scenario>(scenario, logger()).unwrap();
+
+    let err = Connection::new(
+        server.addr(),
+        LOCAL_CONFIG.clone(),
+        "tester",
+        "wrong",
+        logger(),
+    ).err()
+        .expect("login should fail");
+    match *err.kind() {
+        ErrorKind::LoginFailed(10) => {}
+        ref kind => panic!("{:?}", kind),
+    }
+}
 
-    let mut con = Connection::new(*LOCAL_SERVER, &un, &pw, logger.clone()).unwrap();
+#[test]
+fn gdi_names() {
+    let server = DummyServer::start(fixture(), logger()).unwrap();
+    let (mut con, _gbd) = login(&server);
 
-    for pkt in con.read_packets(logger.clone()).expect(
-        "Couldnt read packets",
-    )
-    {
-        process_packet(&mut con, pkt, logger.clone());
+    con.send_packet(ClientPacket::Gdi(1)).unwrap();
+    let mut data_mgr = DataMgr::new();
+    match con.next_response().unwrap() {
+        Some(ServerPacket::Gdi(gdi)) => gge::read_names(&gdi, &mut data_mgr),
+        packet => panic!("expected gdi, got {:?}", packet),
     }
 
-    for _castle in DATAMGR.lock().unwrap().castles.values().take(20) {}
+    assert_eq!(data_mgr.castles[&100].name, Some("Tester castle".to_string()));
+    assert_eq!(data_mgr.castles[&101].name, Some("Tester ice castle".to_string()));
+    assert_eq!(data_mgr.castles[&101].world, Some(World::Ice));
+}
 
-    let mut f = std::fs::OpenOptions::new()
-        .read(false)
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open("data2.json")
+#[test]
+fn gaa_map_import() {
+    let server = DummyServer::start(fixture(), logger()).unwrap();
+    let (mut con, _gbd) = login(&server);
+
+    let area = Area {
+        x1: 0,
+        y1: 0,
+        x2: 12,
+        y2: 12,
+    };
+    con.send_packet(ClientPacket::Gaa(GaaRequest::new(World::Grass, area).unwrap()))
         .unwrap();
+    let answered = match con.next_response().unwrap() {
+        Some(packet @ ServerPacket::Gaa(_)) => {
+            let mut data_mgr = DataMgr::new();
+            let mut requests = Vec::new();
+            Dispatcher::with_extractors()
+                .dispatch(&packet, &mut data_mgr, &mut requests)
+                .unwrap();
+            assert!(requests.is_empty());
+
+            let castle = &data_mgr.castles[&300];
+            assert_eq!(castle.owner_id, Some(3));
+            assert_eq!(castle.name, Some("Neighbour castle".to_string()));
+            assert_eq!((castle.x, castle.y), (Some(5), Some(6)));
+            assert_eq!(data_mgr.users[&3].username, Some("neighbour".to_string()));
+            assert_eq!(data_mgr.users[&3].alliance_id, Some(9));
+            let alliances = data_mgr.alliances_in(World::Grass, area);
+            assert_eq!(alliances.len(), 1);
+            assert_eq!(alliances[0].0.name, Some("Neighbours".to_string()));
+            packet.gaa_area()
+        }
+        packet => panic!("expected gaa, got {:?}", packet),
+    };
+    assert_eq!(answered, Some(area));
+    assert!(con.outstanding().is_empty());
+}
 
-    write!(f, "{}", to_json(&*DATAMGR.lock().unwrap()).unwrap()).unwrap();
+#[test]
+fn reconnect_after_disconnect() {
+    let mut scenario = fixture();
+    scenario.rules.insert(
+        0,
+        serde_json::from_str(
+            r#"{"command": "gdi", "match": {"PID": 2}, "disconnect": true, "times": 1}"#,
+        ).unwrap(),
+    );
+    let server = DummyServer::start(scenario, logger()).unwrap();
+
+    let mut session = Session::connect(
+        server.addr(),
+        LOCAL_CONFIG.clone(),
+        "tester",
+        "secret",
+        logger(),
+    ).unwrap();
+    while session.next_response().unwrap().is_some() {}
+
+    session.send_packet(ClientPacket::Gdi(2)).unwrap();
+    let mut gdi = None;
+    while let Some(packet) = session.next_response().unwrap() {
+        if let ServerPacket::Gdi(data) = packet {
+            gdi = Some(data);
+        }
+    }
+    assert_eq!(gdi.unwrap().castles[0].name, Some("Friend castle".to_string()));
+    assert_eq!(session.reconnects(), 1);
+    assert_eq!(server.connections(), 2);
 }
 
-fn process_packet(con: &mut Connection, pkt: ServerPacket, logger: slog::Logger) {
-    match pkt {
-        ServerPacket::Gbd(ref data) => {
-            let data = &*data;
-            let data = gge::data_extractors::gbd::Gbd::parse_val(data.to_owned()).unwrap();
-            gge::read_castles(data.clone());
-
-            let data_mgr = DATAMGR.lock().unwrap();
-            let users = data_mgr
-                .users
-                .values()
-                .map(|user| user.clone())
-                .collect::<Vec<_>>();
-            for user in users {
-                con.send_packet(ClientPacket::Gdi(user.id)).unwrap();
+#[test]
+fn generated_world_map_scan() {
+    let world = GeneratedWorld::generate(&WorldGenConfig::default());
+    let truth = world.ground_truth();
+    let server = DummyServer::start(world, logger()).unwrap();
+    let (mut con, _gbd) = login(&server);
+
+    let area = Area {
+        x1: 0,
+        y1: 0,
+        x2: 20 * TILE_SIZE - 1,
+        y2: 20 * TILE_SIZE - 1,
+    };
+    let mut scan = MapScan::new(World::Grass, area, TILE_SIZE);
+    let mut dispatcher = Dispatcher::new();
+    dispatcher.register("gaa", map::handle);
+    let mut data_mgr = DataMgr::new();
+    let mut requests = Vec::new();
+    while scan.send_pending(&mut con, 50).unwrap() > 0 {
+        assert_eq!(scan.rounds(), 1);
+        while let Some(packet) = con.next_response().unwrap() {
+            assert!(dispatcher.dispatch(&packet, &mut data_mgr, &mut requests).unwrap());
+            if let ServerPacket::Gaa(ref gaa) = packet {
+                assert!(scan.mark_answered(gaa));
             }
         }
-        ServerPacket::Gdi(data) => {
-            gge::read_names(data).unwrap();
+    }
+    assert!(requests.is_empty());
+    assert!(scan.is_complete());
+
+    let expected = truth
+        .castles
+        .values()
+        .filter(|castle| {
+            castle.world == Some(World::Grass) && castle.x.unwrap() <= area.x2 &&
+                castle.y.unwrap() <= area.y2
+        })
+        .collect::<Vec<_>>();
+    assert!(!expected.is_empty());
+    assert_eq!(data_mgr.castles.len(), expected.len());
+    for castle in expected {
+        assert_eq!(&data_mgr.castles[&castle.id], castle);
+        let owner = castle.owner_id.unwrap();
+        assert_eq!(data_mgr.users[&owner].username, truth.users[&owner].username);
+        assert_eq!(data_mgr.users[&owner].alliance_id, truth.users[&owner].alliance_id);
+    }
+}
+
+#[test]
+fn map_scan_without_echoed_area() {
+    let mut scenario = fixture();
+    scenario.rules.insert(
+        0,
+        serde_json::from_str(
+            r#"{"command": "gaa", "packets": ["%xt%gaa%1%0%{\"KID\":0,\"OI\":[],\"AI\":[]}%"]}"#,
+        ).unwrap(),
+    );
+    let server = DummyServer::start(scenario, logger()).unwrap();
+    let (mut con, _gbd) = login(&server);
+
+    let mut scan = MapScan::new(
+        World::Grass,
+        Area { x1: 0, y1: 0, x2: 25, y2: 12 },
+        TILE_SIZE,
+    );
+    assert_eq!(scan.send_pending(&mut con, 50).unwrap(), 2);
+    let mut answered = Vec::new();
+    while let Some(packet) = con.next_response().unwrap() {
+        if let ServerPacket::Gaa(ref gaa) = packet {
+            assert!(scan.mark_answered(gaa));
+            answered.push(gaa.area.unwrap());
         }
-        _ => {}
-    };
+    }
+    assert!(scan.is_complete());
+    assert_eq!(answered, scan.tiles().to_vec());
+}
+
+#[test]
+fn malformed_packet_quarantined() {
+    let mut scenario = fixture();
+    scenario.rules.insert(
+        0,
+        serde_json::from_str(
+            r#"{"command": "gdi", "match": {"PID": 9}, "packets": ["%xt%gdi%1%0%\"broken\"%"]}"#,
+        ).unwrap(),
+    );
+    let server = DummyServer::start(scenario, logger()).unwrap();
+    let (mut con, _gbd) = login(&server);
+    con.set_deadline(Duration::from_secs(1));
+
+    con.send_packet(ClientPacket::Gdi(9)).unwrap();
+    con.send_packet(ClientPacket::Gdi(2)).unwrap();
+    match con.next_response().unwrap() {
+        Some(ServerPacket::Gdi(gdi)) => assert_eq!(gdi.castles[0].id, 200),
+        packet => panic!("expected gdi, got {:?}", packet),
+    }
+    // the broken answer didn't retire a request, the deadline did
+    assert_eq!(con.outstanding().len(), 1);
+    assert!(con.next_response().unwrap().is_none());
+    assert!(con.outstanding().is_empty());
+
+    let letters = con.dead_letters().letters();
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].text(), r#"%xt%gdi%1%0%"broken"%"#);
+}
+
+#[test]
+fn async_login_and_quarantine() {
+    let mut scenario = fixture();
+    scenario.rules.insert(
+        0,
+        serde_json::from_str(
+            r#"{"command": "gdi", "match": {"PID": 9}, "packets": ["%xt%gdi%1%0%\"broken\"%"]}"#,
+        ).unwrap(),
+    );
+    let server = DummyServer::start(scenario, logger()).unwrap();
+    let mut core = Core::new().unwrap();
+    let handle = core.handle();
+    let con = core.run(AsyncConnection::connect(
+        &server.addr(),
+        LOCAL_CONFIG.clone(),
+        "tester",
+        "secret",
+        &handle,
+        logger(),
+    )).unwrap();
+
+    let (packet, con) = core.run(con.into_future()).map_err(|(err, _)| err).unwrap();
+    match packet {
+        Some(ServerPacket::Gbd(gbd)) => assert_eq!(gbd.data.ain.len(), 2),
+        packet => panic!("expected gbd, got {:?}", packet),
+    }
+
+    let con = core.run(
+        con.send(ClientPacket::Gdi(9))
+            .and_then(|con| con.send(ClientPacket::Gdi(2))),
+    ).unwrap();
+    let (packet, con) = core.run(con.into_future()).map_err(|(err, _)| err).unwrap();
+    match packet {
+        Some(ServerPacket::Gdi(gdi)) => assert_eq!(gdi.castles[0].id, 200),
+        packet => panic!("expected gdi, got {:?}", packet),
+    }
+    let letters = con.dead_letters().letters();
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].text(), r#"%xt%gdi%1%0%"broken"%"#);
 }